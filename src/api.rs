@@ -0,0 +1,541 @@
+//! Requests cloud-storage crate can't make: preconditions, metadata set on upload,
+//! access to specific object generations and customer-supplied or managed encryption keys
+
+use crate::auth::{now, Credentials};
+use crate::crypto::ServerEncryption;
+use crate::error::*;
+use crate::rules::{ObjectProperties, StorageClass};
+use bytes::Bytes;
+use cloud_storage::object::ObjectList;
+use cloud_storage::{GoogleErrorResponse, ListRequest, Object};
+use futures::stream::{self, Stream, StreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use reqwest::{Method, RequestBuilder};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const BASE_URL: &str = "https://storage.googleapis.com/storage/v1";
+const UPLOAD_URL: &str = "https://storage.googleapis.com/upload/storage/v1";
+const BOUNDARY: &str = "cloud_storage_sync_boundary";
+
+const ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'*')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_');
+
+pub(crate) fn percent_encode(input: &str) -> String {
+    utf8_percent_encode(input, ENCODE_SET).to_string()
+}
+
+const CSEK_HEADERS: [&str; 3] = [
+    "x-goog-encryption-algorithm",
+    "x-goog-encryption-key",
+    "x-goog-encryption-key-sha256",
+];
+const COPY_SOURCE_CSEK_HEADERS: [&str; 3] = [
+    "x-goog-copy-source-encryption-algorithm",
+    "x-goog-copy-source-encryption-key",
+    "x-goog-copy-source-encryption-key-sha256",
+];
+
+/// Adds customer-supplied key headers, customer-managed keys are passed as query parameters
+fn insert_csek(
+    headers: &mut HeaderMap,
+    encryption: Option<&ServerEncryption>,
+    names: [&'static str; 3],
+) -> cloud_storage::Result<()> {
+    if let Some(ServerEncryption::CustomerSupplied(key)) = encryption {
+        let [algorithm, key_name, key_sha256] = names;
+        headers.insert(algorithm, HeaderValue::from_static("AES256"));
+        headers.insert(key_name, base64::encode(key).parse()?);
+        headers.insert(key_sha256, ServerEncryption::key_sha256(key).parse()?);
+    }
+    Ok(())
+}
+
+fn kms_key_name(encryption: Option<&ServerEncryption>) -> Option<&str> {
+    match encryption {
+        Some(ServerEncryption::CustomerManaged(name)) => Some(name),
+        _ => None,
+    }
+}
+
+/// Object properties sent along with uploaded content
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NewObject {
+    pub(crate) name: String,
+    pub(crate) content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_control: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_disposition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) storage_class: Option<StorageClass>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temporary_hold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) event_based_hold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) metadata: Option<HashMap<String, String>>,
+}
+
+/// Connections and credentials every request of a sync is sent with
+///
+/// Clones share the connection pool and cached tokens, so sources given clones of one
+/// client reuse connections. The default one authorizes with the service account key
+/// at the path in `SERVICE_ACCOUNT`
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    http: reqwest::Client,
+    credentials: Credentials,
+    token: Arc<Mutex<Option<(String, u64)>>>,
+    billing_project: Option<String>,
+}
+
+impl Client {
+    /// Sends requests with `http`, configured with its own timeouts, proxies and TLS settings
+    pub fn new(http: reqwest::Client) -> Self {
+        Self {
+            http,
+            ..Default::default()
+        }
+    }
+
+    /// Authorizes requests with the credentials, clones made before keep the previous ones
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self.token = Arc::default();
+        self
+    }
+
+    /// Bills requests to the project, required to read requester-pays buckets
+    pub fn with_billing_project(mut self, project: impl Into<String>) -> Self {
+        self.billing_project = Some(project.into());
+        self
+    }
+
+    /// Uploads content and its metadata in a single multipart request
+    ///
+    /// `if_generation_match` of `Some(0)` only succeeds if the object doesn't exist
+    pub(crate) async fn upload<S>(
+        &self,
+        bucket: &str,
+        object: &NewObject,
+        content: S,
+        length: u64,
+        if_generation_match: Option<i64>,
+        encryption: Option<&ServerEncryption>,
+    ) -> cloud_storage::Result<Object>
+    where
+        S: Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    {
+        let mut url = format!(
+            "{}/b/{}/o?uploadType=multipart",
+            UPLOAD_URL,
+            percent_encode(bucket)
+        );
+        if let Some(generation) = if_generation_match {
+            url.push_str(&format!("&ifGenerationMatch={}", generation));
+        }
+        if let Some(name) = kms_key_name(encryption) {
+            url.push_str(&format!("&kmsKeyName={}", percent_encode(name)));
+        }
+
+        let head = format!(
+            "--{b}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{json}\r\n--{b}\r\nContent-Type: {mime}\r\n\r\n",
+            b = BOUNDARY,
+            json = serde_json::to_string(object)?,
+            mime = object.content_type,
+        );
+        let tail = format!("\r\n--{}--\r\n", BOUNDARY);
+        let total = head.len() as u64 + length + tail.len() as u64;
+        let body = stream::once(async move { Ok(Bytes::from(head)) })
+            .chain(content)
+            .chain(stream::once(async move { Ok(Bytes::from(tail)) }));
+
+        let mut headers = self.headers().await?;
+        insert_csek(&mut headers, encryption, CSEK_HEADERS)?;
+        headers.insert(
+            CONTENT_TYPE,
+            format!("multipart/related; boundary={}", BOUNDARY).parse()?,
+        );
+        headers.insert(CONTENT_LENGTH, total.into());
+        let response = self
+            .request(Method::POST, &url)
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+        Self::parse(response).await
+    }
+
+    /// Sets the given properties and custom metadata keys, leaving others as they are
+    pub(crate) async fn patch(
+        &self,
+        bucket: &str,
+        name: &str,
+        properties: &ObjectProperties,
+        if_metageneration_match: Option<i64>,
+    ) -> cloud_storage::Result<Object> {
+        let mut url = format!(
+            "{}/b/{}/o/{}",
+            BASE_URL,
+            percent_encode(bucket),
+            percent_encode(name)
+        );
+        if let Some(metageneration) = if_metageneration_match {
+            url.push_str(&format!("?ifMetagenerationMatch={}", metageneration));
+        }
+        let response = self
+            .request(Method::PATCH, &url)
+            .headers(self.headers().await?)
+            .json(properties)
+            .send()
+            .await?;
+        Self::parse(response).await
+    }
+
+    /// Deletes an object, `if_generation_match` protects a newer generation from deletion
+    pub(crate) async fn delete(
+        &self,
+        bucket: &str,
+        name: &str,
+        if_generation_match: Option<i64>,
+    ) -> cloud_storage::Result<()> {
+        let mut url = format!(
+            "{}/b/{}/o/{}",
+            BASE_URL,
+            percent_encode(bucket),
+            percent_encode(name)
+        );
+        if let Some(generation) = if_generation_match {
+            url.push_str(&format!("?ifGenerationMatch={}", generation));
+        }
+        let response = self
+            .request(Method::DELETE, &url)
+            .headers(self.headers().await?)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Self::error(response).await)
+        }
+    }
+
+    /// Reads object properties, of the live generation if `generation` is `None`
+    ///
+    /// Checksums of objects encrypted with a customer-supplied key are only returned
+    /// when the key is sent, md5 may be missing anyway
+    pub(crate) async fn get(
+        &self,
+        bucket: &str,
+        name: &str,
+        generation: Option<i64>,
+        encryption: Option<&ServerEncryption>,
+    ) -> cloud_storage::Result<Object> {
+        let mut url = format!(
+            "{}/b/{}/o/{}",
+            BASE_URL,
+            percent_encode(bucket),
+            percent_encode(name)
+        );
+        if let Some(generation) = generation {
+            url.push_str(&format!("?generation={}", generation));
+        }
+        let mut headers = self.headers().await?;
+        insert_csek(&mut headers, encryption, CSEK_HEADERS)?;
+        let response = self
+            .request(Method::GET, &url)
+            .headers(headers)
+            .send()
+            .await?;
+        Self::parse(response).await
+    }
+
+    /// Lists objects page by page
+    ///
+    /// Listings don't include checksums of objects encrypted with customer-supplied keys,
+    /// such objects are read one by one with the key
+    pub(crate) fn list<'a>(
+        &'a self,
+        bucket: &'a str,
+        request: ListRequest,
+        encryption: Option<&'a ServerEncryption>,
+    ) -> impl Stream<Item = cloud_storage::Result<ObjectList>> + 'a {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Page {
+            #[serde(default)]
+            items: Vec<serde_json::Value>,
+            #[serde(default)]
+            prefixes: Vec<String>,
+            next_page_token: Option<String>,
+        }
+
+        let request = std::sync::Arc::new(request);
+        stream::try_unfold(Some(None), move |page_token: Option<Option<String>>| {
+            let request = request.clone();
+            async move {
+                let page_token = match page_token {
+                    Some(page_token) => page_token,
+                    None => return Ok(None),
+                };
+                let mut query = vec![];
+                if let Some(prefix) = &request.prefix {
+                    query.push(("prefix", prefix.clone()));
+                }
+                if let Some(delimiter) = &request.delimiter {
+                    query.push(("delimiter", delimiter.clone()));
+                }
+                if let Some(versions) = request.versions {
+                    query.push(("versions", versions.to_string()));
+                }
+                if let Some(page_token) = page_token {
+                    query.push(("pageToken", page_token));
+                }
+                let url = format!("{}/b/{}/o", BASE_URL, percent_encode(bucket));
+                let response = self
+                    .request(Method::GET, &url)
+                    .headers(self.headers().await?)
+                    .query(&query)
+                    .send()
+                    .await?;
+                let page: Page = Self::parse(response).await?;
+
+                let mut items = Vec::with_capacity(page.items.len());
+                for item in page.items {
+                    if item.get("crc32c").is_some() {
+                        items.push(serde_json::from_value(item)?);
+                        continue;
+                    }
+                    let name = item.get("name").and_then(|name| name.as_str());
+                    let generation = item
+                        .get("generation")
+                        .and_then(|generation| generation.as_str())
+                        .and_then(|generation| generation.parse().ok());
+                    match name {
+                        Some(name) => {
+                            items.push(self.get(bucket, name, generation, encryption).await?)
+                        }
+                        None => return Err(cloud_storage::Error::Other(item.to_string())),
+                    }
+                }
+                let next = page.next_page_token.map(Some);
+                let list = ObjectList {
+                    items,
+                    prefixes: page.prefixes,
+                    next_page_token: next.clone().flatten(),
+                    ..Default::default()
+                };
+                Ok(Some((list, next)))
+            }
+        })
+    }
+
+    /// Starts downloading object content, the live generation if `generation` is `None`
+    pub(crate) async fn download(
+        &self,
+        bucket: &str,
+        name: &str,
+        generation: Option<i64>,
+        encryption: Option<&ServerEncryption>,
+    ) -> cloud_storage::Result<reqwest::Response> {
+        let mut url = format!(
+            "{}/b/{}/o/{}?alt=media",
+            BASE_URL,
+            percent_encode(bucket),
+            percent_encode(name)
+        );
+        if let Some(generation) = generation {
+            url.push_str(&format!("&generation={}", generation));
+        }
+        let mut headers = self.headers().await?;
+        insert_csek(&mut headers, encryption, CSEK_HEADERS)?;
+        // stored bytes are wanted, gzip encoded content is decompressed by Cloud Storage otherwise
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = self
+            .request(Method::GET, &url)
+            .headers(headers)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(Self::error(response).await)
+        }
+    }
+
+    /// Server-side copy of the source object generation,
+    /// repeats the rewrite request until large objects are copied completely
+    ///
+    /// The copy keeps the source storage class unless `storage_class` is set,
+    /// `encryption` is used for both the source and the destination
+    pub(crate) async fn rewrite(
+        &self,
+        source: &Object,
+        dst_bucket: &str,
+        dst_name: &str,
+        storage_class: Option<StorageClass>,
+        if_generation_match: Option<i64>,
+        encryption: Option<&ServerEncryption>,
+    ) -> cloud_storage::Result<Object> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct RewriteResponse {
+            done: bool,
+            rewrite_token: Option<String>,
+            resource: Option<Object>,
+        }
+
+        let mut url = format!(
+            "{}/b/{}/o/{}/rewriteTo/b/{}/o/{}?sourceGeneration={}",
+            BASE_URL,
+            percent_encode(&source.bucket),
+            percent_encode(&source.name),
+            percent_encode(dst_bucket),
+            percent_encode(dst_name),
+            source.generation
+        );
+        if let Some(generation) = if_generation_match {
+            url.push_str(&format!("&ifGenerationMatch={}", generation));
+        }
+        // a destination resource replaces properties of the source, so they're repeated
+        let destination = storage_class.map(|storage_class| {
+            serde_json::json!({
+                "contentType": source.content_type,
+                "contentEncoding": source.content_encoding,
+                "contentDisposition": source.content_disposition,
+                "contentLanguage": source.content_language,
+                "cacheControl": source.cache_control,
+                "metadata": source.metadata,
+                "storageClass": storage_class,
+            })
+        });
+        if let Some(name) = kms_key_name(encryption) {
+            url.push_str(&format!("&destinationKmsKeyName={}", percent_encode(name)));
+        }
+        let mut rewrite_token: Option<String> = None;
+        loop {
+            let url = match &rewrite_token {
+                Some(token) => format!("{}&rewriteToken={}", url, percent_encode(token)),
+                None => url.clone(),
+            };
+            let mut headers = self.headers().await?;
+            insert_csek(&mut headers, encryption, CSEK_HEADERS)?;
+            insert_csek(&mut headers, encryption, COPY_SOURCE_CSEK_HEADERS)?;
+            let request = self.request(Method::POST, &url).headers(headers);
+            let request = match &destination {
+                Some(destination) => request.json(destination),
+                None => request.header(CONTENT_LENGTH, 0),
+            };
+            let response = request.send().await?;
+            let response: RewriteResponse = Self::parse(response).await?;
+            match (response.done, response.resource) {
+                (true, Some(object)) => return Ok(object),
+                (true, None) => {
+                    return Err(cloud_storage::Error::Other(
+                        "Rewrite finished without a resource".to_owned(),
+                    ))
+                }
+                (false, _) => rewrite_token = response.rewrite_token,
+            }
+        }
+    }
+
+    async fn parse<T: serde::de::DeserializeOwned>(
+        response: reqwest::Response,
+    ) -> cloud_storage::Result<T> {
+        if response.status().is_success() {
+            let text = response.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            Err(Self::error(response).await)
+        }
+    }
+
+    async fn error(response: reqwest::Response) -> cloud_storage::Error {
+        let text = match response.text().await {
+            Ok(text) => text,
+            Err(e) => return e.into(),
+        };
+        match serde_json::from_str::<GoogleErrorResponse>(&text) {
+            Ok(error) => cloud_storage::Error::Google(error),
+            Err(_) => cloud_storage::Error::Other(text),
+        }
+    }
+
+    /// Request billed to the billing project if there's one
+    pub(crate) fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.http.request(method, url);
+        match &self.billing_project {
+            Some(project) => request.query(&[("userProject", project)]),
+            None => request,
+        }
+    }
+
+    async fn headers(&self) -> cloud_storage::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", self.token().await?).parse()?,
+        );
+        Ok(headers)
+    }
+
+    /// Access token of the credentials, cached until a minute before it expires
+    async fn token(&self) -> cloud_storage::Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((access_token, exp)) = token.as_ref() {
+            if *exp > now() + 60 {
+                return Ok(access_token.clone());
+            }
+        }
+        let (access_token, exp) = self.credentials.token(&self.http).await?;
+        *token = exp.map(|exp| (access_token.clone(), exp));
+        Ok(access_token)
+    }
+}
+
+/// Whether a request failed because of an `ifGenerationMatch` or `ifMetagenerationMatch` precondition
+pub(crate) fn is_precondition_failure(error: &cloud_storage::Error) -> bool {
+    matches!(error, cloud_storage::Error::Google(response)
+        if response.errors_has_reason(&cloud_storage::Reason::ConditionNotMet))
+}
+
+/// Whether a request failed because the object doesn't exist
+pub(crate) fn is_not_found(error: &cloud_storage::Error) -> bool {
+    matches!(error, cloud_storage::Error::Google(response)
+        if response.errors_has_reason(&cloud_storage::Reason::NotFound))
+}
+
+/// Adds context to an upload or update result,
+/// precondition failures become [Error::PreconditionFailed]
+pub(crate) fn check_precondition<T>(
+    result: cloud_storage::Result<T>,
+    bucket: &str,
+    object: &str,
+    op: OpSource,
+) -> crate::Result<T> {
+    match result {
+        Err(e) if is_precondition_failure(&e) => Err(Error::PreconditionFailed {
+            bucket: bucket.to_owned(),
+            object: object.to_owned(),
+        }),
+        result => result.context(CloudStorage {
+            object: object.to_owned(),
+            op,
+        }),
+    }
+}
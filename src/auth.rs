@@ -0,0 +1,193 @@
+//! Credentials requests are authorized with
+
+use crate::error::*;
+use crate::Result;
+use futures::future::{BoxFuture, FutureExt};
+use reqwest::header::HeaderValue;
+use snafu::ResultExt;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TOKEN_URL: &str = "https://www.googleapis.com/oauth2/v4/token";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.full_control";
+
+/// Error returned by a token provider
+pub type TokenError = Box<dyn std::error::Error + Send + Sync>;
+
+type TokenProvider = Arc<dyn Fn() -> BoxFuture<'static, Result<String, TokenError>> + Send + Sync>;
+
+/// Identity a [LocalSource](crate::LocalSource) or a [GcsSource](crate::GcsSource)
+/// syncs with, the service account key at the path in `SERVICE_ACCOUNT` by default
+#[derive(Clone, Default)]
+pub struct Credentials(Source);
+
+#[derive(Clone, Default)]
+enum Source {
+    #[default]
+    Environment,
+    ServiceAccount(Arc<ServiceAccountKey>),
+    Provider(TokenProvider),
+    MetadataServer,
+}
+
+#[derive(serde::Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+impl Credentials {
+    /// Service account key json, as downloaded from the Cloud Console
+    pub fn from_service_account_json(json: impl AsRef<[u8]>) -> Result<Self> {
+        let key = serde_json::from_slice(json.as_ref()).context(InvalidServiceAccount)?;
+        Ok(Self(Source::ServiceAccount(Arc::new(key))))
+    }
+
+    /// Service account key json file
+    pub fn from_service_account_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read(path).context(Io { path })?;
+        Self::from_service_account_json(json)
+    }
+
+    /// OAuth2 access tokens with a Cloud Storage scope returned by `provider`,
+    /// which is called before each request, so it should cache tokens until they expire
+    pub fn from_token_provider<F, T>(provider: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+        T: Future<Output = Result<String, TokenError>> + Send + 'static,
+    {
+        Self(Source::Provider(Arc::new(move || provider().boxed())))
+    }
+
+    /// Tokens of the service account attached to the Compute Engine instance,
+    /// GKE workload or Cloud Run service the sync runs in
+    pub fn from_metadata_server() -> Self {
+        Self(Source::MetadataServer)
+    }
+
+    /// An access token and when it expires, in seconds since the epoch,
+    /// tokens without expiry aren't cached
+    pub(crate) async fn token(
+        &self,
+        http: &reqwest::Client,
+    ) -> cloud_storage::Result<(String, Option<u64>)> {
+        #[derive(serde::Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            expires_in: u64,
+        }
+
+        let now = now();
+        let response: TokenResponse = match &self.0 {
+            Source::Environment => {
+                let account = &*cloud_storage::SERVICE_ACCOUNT;
+                let jwt = assertion(&account.client_email, &account.private_key, TOKEN_URL)?;
+                exchange(http, TOKEN_URL, &jwt).await?.json().await?
+            }
+            Source::ServiceAccount(key) => {
+                let token_uri = key.token_uri.as_deref().unwrap_or(TOKEN_URL);
+                let jwt = assertion(&key.client_email, &key.private_key, token_uri)?;
+                exchange(http, token_uri, &jwt).await?.json().await?
+            }
+            Source::Provider(provider) => {
+                let token = provider()
+                    .await
+                    .map_err(|e| cloud_storage::Error::Other(e.to_string()))?;
+                return Ok((token, None));
+            }
+            Source::MetadataServer => {
+                let response = http
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", HeaderValue::from_static("Google"))
+                    .send()
+                    .await?;
+                checked(response).await?.json().await?
+            }
+        };
+        Ok((response.access_token, Some(now + response.expires_in)))
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Source::Environment => f.write_str("Credentials::Environment"),
+            Source::ServiceAccount(key) => {
+                write!(f, "Credentials::ServiceAccount({})", key.client_email)
+            }
+            Source::Provider(_) => f.write_str("Credentials::Provider"),
+            Source::MetadataServer => f.write_str("Credentials::MetadataServer"),
+        }
+    }
+}
+
+/// Signed JWT a service account exchanges for an access token
+fn assertion(
+    client_email: &str,
+    private_key: &str,
+    audience: &str,
+) -> cloud_storage::Result<String> {
+    #[derive(serde::Serialize)]
+    struct Claims<'a> {
+        iss: &'a str,
+        scope: &'a str,
+        aud: &'a str,
+        exp: u64,
+        iat: u64,
+    }
+
+    let now = now();
+    let claims = Claims {
+        iss: client_email,
+        scope: SCOPE,
+        aud: audience,
+        exp: now + 3600,
+        iat: now,
+    };
+    let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+    let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())?;
+    Ok(jsonwebtoken::encode(&header, &claims, &key)?)
+}
+
+async fn exchange(
+    http: &reqwest::Client,
+    token_uri: &str,
+    jwt: &str,
+) -> cloud_storage::Result<reqwest::Response> {
+    let response = http
+        .post(token_uri)
+        .form(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", jwt),
+        ])
+        .send()
+        .await?;
+    checked(response).await
+}
+
+/// Token endpoints don't answer with Cloud Storage errors
+async fn checked(response: reqwest::Response) -> cloud_storage::Result<reqwest::Response> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        Err(cloud_storage::Error::Other(format!(
+            "Token request failed with {}: {}",
+            status, text
+        )))
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
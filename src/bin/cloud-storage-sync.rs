@@ -0,0 +1,122 @@
+//! `gsutil rsync`-like command line interface
+
+use clap::Parser;
+use cloud_storage_sync::error::Error;
+use cloud_storage_sync::{Credentials, Filter, Location, Sync};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Instant;
+
+/// Syncs local files and Google Cloud Storage buckets
+///
+/// Credentials are read from the service account json key given with --credentials,
+/// or at the path in the SERVICE_ACCOUNT environment variable
+#[derive(Debug, Parser)]
+#[clap(version)]
+struct Args {
+    /// Local path or gs://bucket/prefix to sync from
+    src: Location,
+    /// Local path or gs://bucket/prefix to sync to
+    dst: Location,
+    /// Number of concurrent uploads or downloads
+    #[clap(short = 'j', long, default_value = "4")]
+    concurrency: usize,
+    /// Upload or download every file without comparing it, replacing local files
+    /// and directories in the way of downloads
+    #[clap(short, long)]
+    force: bool,
+    /// Sync only files matching a glob, may be repeated
+    #[clap(short, long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip files and directories matching a glob, may be repeated
+    #[clap(short = 'x', long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Print what would be synced without changing anything
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+    /// Service account json key to authorize with
+    #[clap(long, value_name = "FILE")]
+    credentials: Option<PathBuf>,
+    /// Project billed for requests, needed by requester-pays buckets
+    #[clap(short = 'u', long, value_name = "PROJECT")]
+    billing_project: Option<String>,
+    /// Log each operation, twice for trace logs
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: usize,
+}
+
+fn filter(args: &Args) -> Result<Filter, Error> {
+    let filter = args
+        .include
+        .iter()
+        .try_fold(Filter::default(), |filter, glob| filter.with_include(glob))?;
+    args.exclude
+        .iter()
+        .try_fold(filter, |filter, glob| filter.with_exclude(glob))
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    // planned operations are logged at info level
+    let level = match args.verbose {
+        0 if !args.dry_run => "warn",
+        0 | 1 => "info",
+        _ => "trace",
+    };
+    let level = format!("cloud_storage_sync={}", level);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    let filter = match filter(&args) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+    let mut builder = Sync::builder()
+        .with_concurrency(args.concurrency)
+        .with_force_overwrite(args.force)
+        .with_filter(filter)
+        .with_dry_run(args.dry_run);
+    if let Some(path) = &args.credentials {
+        match Credentials::from_service_account_file(path) {
+            Ok(credentials) => builder = builder.with_credentials(credentials),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        }
+    }
+    if let Some(project) = &args.billing_project {
+        builder = builder.with_billing_project(project);
+    }
+    let sync = builder.build();
+
+    let started = Instant::now();
+    let synced = match (&args.src, &args.dst) {
+        (Location::Local(_), Location::Local(_)) => {
+            eprintln!("At least one of the locations must be gs://bucket/prefix");
+            exit(2);
+        }
+        (src, dst) => sync.sync(src, dst).await,
+    };
+
+    match synced {
+        Ok(count) if args.dry_run => println!(
+            "{} to {}: {} operations would be made",
+            args.src, args.dst, count
+        ),
+        Ok(count) => println!(
+            "{} to {}: {} operations in {:.1}s",
+            args.src,
+            args.dst,
+            count,
+            started.elapsed().as_secs_f64()
+        ),
+        Err(e) => {
+            eprintln!("{} to {} failed: {}", args.src, args.dst, e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Gzip compression of uploaded content, stored with `Content-Encoding: gzip`
+//!
+//! Size and crc32c of uncompressed content are kept in custom metadata,
+//! so local files are compared to what they would be after decompression.
+
+use crate::crypto::{PLAINTEXT_CRC32C_KEY, PLAINTEXT_SIZE_KEY};
+use cloud_storage::object::Object;
+use flate2::write::{GzDecoder, GzEncoder};
+use mime::Mime;
+use std::collections::HashMap;
+use std::io::{SeekFrom, Write};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+pub(crate) const GZIP_ENCODING: &str = "gzip";
+
+/// Which files are gzipped on upload
+///
+/// A file is compressed if either its extension or the mime type guessed from it match,
+/// a rule set without extensions and mime types compresses nothing
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Gzip {
+    extensions: Vec<String>,
+    mime_types: Vec<String>,
+    level: Option<u32>,
+}
+
+impl Gzip {
+    /// Compresses files with an extension, e.g. `html`, case insensitive
+    pub fn with_extension(mut self, extension: impl Into<String>) -> Self {
+        self.extensions
+            .push(extension.into().trim_start_matches('.').to_lowercase());
+        self
+    }
+
+    /// Compresses files of a mime type, e.g. `application/json`,
+    /// `text/*` matches any subtype
+    pub fn with_mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.mime_types.push(mime_type.into().to_lowercase());
+        self
+    }
+
+    /// Compression level from 0 to 9, 6 by default
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = Some(level.min(9));
+        self
+    }
+
+    pub(crate) fn matches(&self, path: &Path, mime_type: &Mime) -> bool {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+        if extension.is_some_and(|extension| self.extensions.contains(&extension)) {
+            return true;
+        }
+        self.mime_types
+            .iter()
+            .any(|pattern| match pattern.split_once('/') {
+                Some((type_, "*")) => type_ == mime_type.type_().as_str(),
+                _ => pattern == mime_type.essence_str(),
+            })
+    }
+
+    /// Compresses a file piece by piece into an anonymous temporary file,
+    /// so its length is known before the upload starts.
+    /// Returns the compressed file positioned at its start and its length
+    pub(crate) async fn compress_file(&self, mut file: File) -> std::io::Result<(File, u64)> {
+        let level = self
+            .level
+            .map_or_else(flate2::Compression::default, flate2::Compression::new);
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        let mut compressed = File::from_std(tempfile::tempfile()?);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            encoder.write_all(&buffer[..read])?;
+            compressed
+                .write_all(&std::mem::take(encoder.get_mut()))
+                .await?;
+        }
+        compressed.write_all(&encoder.finish()?).await?;
+        let len = compressed.seek(SeekFrom::Current(0)).await?;
+        compressed.seek(SeekFrom::Start(0)).await?;
+        Ok((compressed, len))
+    }
+}
+
+/// Metadata describing uncompressed content
+pub(crate) fn gzip_metadata(len: u64, crc32c: u32) -> HashMap<String, String> {
+    vec![
+        (PLAINTEXT_SIZE_KEY.to_owned(), len.to_string()),
+        (PLAINTEXT_CRC32C_KEY.to_owned(), crc32c.to_string()),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// Whether an object is stored with `Content-Encoding: gzip`
+pub(crate) fn is_gzipped(object: &Object) -> bool {
+    object
+        .content_encoding
+        .as_deref()
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case(GZIP_ENCODING))
+}
+
+/// Decompresses content received in pieces of any size
+pub(crate) struct Gunzip {
+    decoder: GzDecoder<Vec<u8>>,
+}
+
+impl Gunzip {
+    pub(crate) fn new() -> Self {
+        Self {
+            decoder: GzDecoder::new(Vec::new()),
+        }
+    }
+
+    /// Returns content decompressed so far
+    pub(crate) fn push(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.decoder.write_all(data)?;
+        Ok(std::mem::take(self.decoder.get_mut()))
+    }
+
+    /// Returns the rest of the content, fails if it's truncated
+    pub(crate) fn finish(self) -> std::io::Result<Vec<u8>> {
+        self.decoder.finish()
+    }
+}
@@ -0,0 +1,362 @@
+//! Client-side authenticated encryption of object content
+//!
+//! Content is encrypted with AES-256-GCM in segments of 64KiB, each with its own nonce and tag:
+//! a header of a version byte and a random 8 bytes nonce prefix is followed by
+//! `[ciphertext][16 bytes tag]` of every segment. Nonces are the prefix followed by
+//! the segment number, the last segment, possibly empty, is authenticated as such,
+//! so reordered, truncated or extended content fails to decrypt.
+//!
+//! Every upload gets a random content id stored in metadata, segments authenticate it
+//! along with the key id. Content moved to another object without its metadata fails
+//! to decrypt, while metadata itself isn't protected. Objects copied server-side keep
+//! their metadata and so remain readable under any name.
+//!
+//! Keys of server-side encryption, customer-supplied or customer-managed, are defined here too.
+
+use crate::compression::is_gzipped;
+use crate::util::*;
+use bytes::Bytes;
+use cloud_storage::object::Object;
+use futures::stream::{self, Stream};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Custom metadata keys of encrypted objects
+pub(crate) const ENCRYPTION_KEY: &str = "cloud-storage-sync-encryption";
+pub(crate) const KEY_ID_KEY: &str = "cloud-storage-sync-key-id";
+pub(crate) const PLAINTEXT_SIZE_KEY: &str = "cloud-storage-sync-plaintext-size";
+pub(crate) const PLAINTEXT_CRC32C_KEY: &str = "cloud-storage-sync-plaintext-crc32c";
+pub(crate) const CONTENT_ID_KEY: &str = "cloud-storage-sync-content-id";
+
+const ALGORITHM: &str = "aes-256-gcm-segments";
+const VERSION: u8 = 1;
+const SEGMENT: usize = 64 * 1024;
+const TAG: usize = 16;
+const PREFIX: usize = 8;
+const HEADER: usize = 1 + PREFIX;
+
+/// A key for client-side encryption of object content
+///
+/// Only file content is encrypted, object names and metadata are stored as is
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+    id: String,
+}
+
+impl EncryptionKey {
+    /// A 256 bit AES key
+    pub fn new(key: [u8; 32]) -> Self {
+        let mut input = b"cloud-storage-sync key id ".to_vec();
+        input.extend_from_slice(&key);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &input);
+        let id = digest.as_ref()[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self { key, id }
+    }
+
+    /// Key fingerprint stored in metadata of objects encrypted with this key
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        // the length is always right for AES-256
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).unwrap())
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// Size of encrypted content
+pub(crate) fn encrypted_len(plaintext_len: u64) -> u64 {
+    let segments = plaintext_len / SEGMENT as u64 + 1;
+    HEADER as u64 + plaintext_len + segments * TAG as u64
+}
+
+/// Associated data of a segment
+fn aad(context: &[u8], last: bool) -> Aad<Vec<u8>> {
+    let mut aad = vec![last as u8];
+    aad.extend_from_slice(context);
+    Aad::from(aad)
+}
+
+/// What content of an object is bound to
+fn aad_context(key: &EncryptionKey, content_id: &str) -> Vec<u8> {
+    format!("{}/{}", key.id(), content_id).into_bytes()
+}
+
+fn random<const N: usize>(what: &str) -> std::io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| std::io::Error::other(format!("no random numbers for {}", what)))?;
+    Ok(bytes)
+}
+
+/// A random id for new content
+pub(crate) fn new_content_id() -> std::io::Result<String> {
+    Ok(random::<16>("a content id")?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn nonce(prefix: &[u8; PREFIX], counter: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX].copy_from_slice(prefix);
+    nonce[PREFIX..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+pub(crate) struct Encryptor {
+    key: LessSafeKey,
+    context: Vec<u8>,
+    prefix: [u8; PREFIX],
+    counter: u32,
+}
+
+impl Encryptor {
+    /// Returns the encryptor of content identified by `content_id` and the content header
+    pub(crate) fn new(key: &EncryptionKey, content_id: &str) -> std::io::Result<(Self, Vec<u8>)> {
+        let prefix = random::<PREFIX>("a nonce")?;
+        let mut header = vec![VERSION];
+        header.extend_from_slice(&prefix);
+        let encryptor = Self {
+            key: key.aead_key(),
+            context: aad_context(key, content_id),
+            prefix,
+            counter: 0,
+        };
+        Ok((encryptor, header))
+    }
+
+    pub(crate) fn seal(&mut self, plaintext: &[u8], last: bool) -> std::io::Result<Vec<u8>> {
+        let mut segment = plaintext.to_vec();
+        let nonce = nonce(&self.prefix, self.counter);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::other("too many segments"))?;
+        self.key
+            .seal_in_place_append_tag(nonce, aad(&self.context, last), &mut segment)
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        Ok(segment)
+    }
+}
+
+/// Decrypts content received in pieces of any size
+pub(crate) struct Decryptor {
+    key: LessSafeKey,
+    /// `None` for objects without a content id, nothing is readable then
+    context: Option<Vec<u8>>,
+    prefix: Option<[u8; PREFIX]>,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl Decryptor {
+    /// Decryptor of content stored with `content_id` in metadata
+    pub(crate) fn new(key: &EncryptionKey, content_id: Option<&str>) -> Self {
+        Self {
+            key: key.aead_key(),
+            context: content_id.map(|content_id| aad_context(key, content_id)),
+            prefix: None,
+            counter: 0,
+            buffer: vec![],
+        }
+    }
+
+    /// Returns plaintext of the segments completed by `data`
+    pub(crate) fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        self.buffer.extend_from_slice(data);
+        let prefix = match self.prefix {
+            Some(prefix) => prefix,
+            None if self.buffer.len() < HEADER => return Ok(vec![]),
+            None => {
+                if self.buffer[0] != VERSION {
+                    return Err("unknown format version");
+                }
+                if self.context.is_none() {
+                    return Err("no content id");
+                }
+                let prefix = *array_ref!(self.buffer, 1, PREFIX);
+                self.buffer.drain(..HEADER);
+                self.prefix = Some(prefix);
+                prefix
+            }
+        };
+        let mut plaintext = vec![];
+        // a full segment is known not to be the last one only when more data follows it
+        while self.buffer.len() > SEGMENT + TAG {
+            let mut segment: Vec<u8> = self.buffer.drain(..SEGMENT + TAG).collect();
+            plaintext.extend_from_slice(self.open(&prefix, &mut segment, false)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// Returns plaintext of the last segment
+    pub(crate) fn finish(mut self) -> Result<Vec<u8>, &'static str> {
+        let prefix = self.prefix.ok_or("truncated content")?;
+        let mut segment = std::mem::take(&mut self.buffer);
+        Ok(self.open(&prefix, &mut segment, true)?.to_vec())
+    }
+
+    fn open<'a>(
+        &mut self,
+        prefix: &[u8; PREFIX],
+        segment: &'a mut [u8],
+        last: bool,
+    ) -> Result<&'a [u8], &'static str> {
+        let nonce = nonce(prefix, self.counter);
+        self.counter = self.counter.checked_add(1).ok_or("too many segments")?;
+        // checked along with the prefix
+        let context = self.context.as_deref().unwrap_or_default();
+        self.key
+            .open_in_place(nonce, aad(context, last), segment)
+            .map(|plaintext| &*plaintext)
+            .map_err(|_| "wrong key or corrupted content")
+    }
+}
+
+/// Encrypted content of a file of `len` bytes identified by `content_id`
+pub(crate) fn encrypt_file(
+    key: &EncryptionKey,
+    content_id: &str,
+    file: File,
+    len: u64,
+) -> std::io::Result<impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static> {
+    let (encryptor, header) = Encryptor::new(key, content_id)?;
+    let header = stream::once(async move { Ok(Bytes::from(header)) });
+    let segments = stream::try_unfold(
+        (file, encryptor, Some(len)),
+        |(mut file, mut encryptor, remaining)| async move {
+            let remaining = match remaining {
+                Some(remaining) => remaining,
+                None => return Ok(None),
+            };
+            let segment_len = remaining.min(SEGMENT as u64) as usize;
+            let mut plaintext = vec![0; segment_len];
+            file.read_exact(&mut plaintext).await?;
+            // a full segment is followed by another one, possibly empty
+            let last = segment_len < SEGMENT;
+            let segment = encryptor.seal(&plaintext, last)?;
+            let remaining = remaining - segment_len as u64;
+            let next = if last { None } else { Some(remaining) };
+            Ok(Some((Bytes::from(segment), (file, encryptor, next))))
+        },
+    );
+    Ok(futures::StreamExt::chain(header, segments))
+}
+
+/// Metadata describing content encrypted with `key`
+pub(crate) fn encryption_metadata(
+    key: &EncryptionKey,
+    content_id: &str,
+    plaintext_len: u64,
+    plaintext_crc32c: u32,
+) -> HashMap<String, String> {
+    vec![
+        (ENCRYPTION_KEY, ALGORITHM.to_owned()),
+        (KEY_ID_KEY, key.id().to_owned()),
+        (CONTENT_ID_KEY, content_id.to_owned()),
+        (PLAINTEXT_SIZE_KEY, plaintext_len.to_string()),
+        (PLAINTEXT_CRC32C_KEY, plaintext_crc32c.to_string()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_owned(), value))
+    .collect()
+}
+
+/// Id of the key an object is encrypted with, `None` for objects stored unencrypted
+pub(crate) fn encryption_key_id(object: &Object) -> Option<&str> {
+    let metadata = object.metadata.as_ref()?;
+    metadata.get(ENCRYPTION_KEY)?;
+    Some(metadata.get(KEY_ID_KEY).map_or("", String::as_str))
+}
+
+/// Content id of an encrypted object, `None` for legacy content
+pub(crate) fn content_id(object: &Object) -> Option<&str> {
+    object
+        .metadata
+        .as_ref()?
+        .get(CONTENT_ID_KEY)
+        .map(String::as_str)
+}
+
+/// Whether an object is stored the way it would be uploaded with `key`
+pub(crate) fn encrypted_with(object: &Object, key: Option<&EncryptionKey>) -> bool {
+    encryption_key_id(object) == key.map(EncryptionKey::id)
+}
+
+/// Size and crc32c of the content an object holds, before compression or encryption
+pub(crate) fn plaintext_size_crc32c(object: &Object) -> (u64, u32) {
+    let stored = object.metadata.as_ref().and_then(|metadata| {
+        if metadata.get(ENCRYPTION_KEY).is_none() && !is_gzipped(object) {
+            return None;
+        }
+        let size = metadata.get(PLAINTEXT_SIZE_KEY)?.parse().ok()?;
+        let crc32c = metadata.get(PLAINTEXT_CRC32C_KEY)?.parse().ok()?;
+        Some((size, crc32c))
+    });
+    stored.unwrap_or_else(|| (object.size, object.crc32c_decode()))
+}
+
+/// Encryption Cloud Storage applies to stored objects with keys the caller controls
+#[derive(Clone, PartialEq, Eq)]
+pub enum ServerEncryption {
+    /// Customer-supplied AES-256 key (CSEK), sent along with every request
+    /// reading or writing object content, it's never stored by Google
+    CustomerSupplied([u8; 32]),
+    /// Customer-managed Cloud KMS key (CMEK) new objects are encrypted with,
+    /// `projects/[project]/locations/[location]/keyRings/[ring]/cryptoKeys/[key]`
+    CustomerManaged(String),
+}
+
+impl ServerEncryption {
+    /// Base64 encoded sha256 of a customer-supplied key, as Cloud Storage reports it
+    pub(crate) fn key_sha256(key: &[u8; 32]) -> String {
+        base64::encode(ring::digest::digest(&ring::digest::SHA256, key))
+    }
+
+    /// Whether an object is encrypted the way it would be uploaded with `encryption`,
+    /// without one only objects not needing a customer-supplied key match
+    pub(crate) fn applied_to(encryption: Option<&Self>, object: &Object) -> bool {
+        match encryption {
+            None => object.customer_encryption.is_none(),
+            Some(Self::CustomerSupplied(key)) => object
+                .customer_encryption
+                .as_ref()
+                .is_some_and(|applied| applied.key_sha256 == Self::key_sha256(key)),
+            // the name of the key version used is reported
+            Some(Self::CustomerManaged(name)) => {
+                object.kms_key_name.as_ref().is_some_and(|applied| {
+                    applied == name || applied.starts_with(&format!("{}/cryptoKeyVersions/", name))
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ServerEncryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CustomerSupplied(key) => f
+                .debug_tuple("CustomerSupplied")
+                .field(&Self::key_sha256(key))
+                .finish(),
+            Self::CustomerManaged(name) => f.debug_tuple("CustomerManaged").field(name).finish(),
+        }
+    }
+}
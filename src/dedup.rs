@@ -0,0 +1,520 @@
+//! Content-addressed backup store: file contents are split into content-defined chunks
+//! stored once under their hash, every snapshot is a manifest listing chunks of its files
+
+use crate::api::*;
+use crate::error::*;
+use crate::gcs::GcsSource;
+use crate::local::{LocalSource, SymlinkPolicy};
+use crate::names::*;
+use crate::posix::*;
+use crate::util::FileUtil;
+use crate::Result;
+use bytes::Bytes;
+use futures::stream::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MIN_CHUNK: usize = 256 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// 20 top bits of the rolling hash, a boundary every 1MiB on average past the minimum
+const BOUNDARY_MASK: u64 = !0 << 44;
+
+/// Random values for the gear rolling hash, generated with splitmix64
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the chunk starting at `data`,
+/// `data` must hold at least the maximum chunk size unless it's the end of a file
+pub(crate) fn chunk_len(data: &[u8]) -> usize {
+    let end = data.len().min(MAX_CHUNK);
+    let mut hash = 0u64;
+    for (i, byte) in data[..end].iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if i + 1 >= MIN_CHUNK && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Reads a file chunk by chunk
+struct ChunkReader {
+    file: File,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl ChunkReader {
+    async fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).await.context(TokioIo { path })?;
+        Ok(Self {
+            file,
+            buffer: Vec::with_capacity(MAX_CHUNK),
+            eof: false,
+        })
+    }
+
+    async fn next(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.buffer.len() < MAX_CHUNK {
+            let len = self.buffer.len();
+            self.buffer.resize(MAX_CHUNK, 0);
+            let read = self.file.read(&mut self.buffer[len..]).await?;
+            self.buffer.truncate(len + read);
+            self.eof = read == 0;
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let len = chunk_len(&self.buffer);
+        Ok(Some(self.buffer.drain(..len).collect()))
+    }
+}
+
+/// Hashes of the chunks a local file consists of
+async fn file_chunks(path: &Path) -> Result<Vec<String>> {
+    let mut reader = ChunkReader::open(path).await?;
+    let mut chunks = vec![];
+    while let Some(chunk) = reader.next().await.context(TokioIo { path })? {
+        chunks.push(sha256_hex(&chunk));
+    }
+    Ok(chunks)
+}
+
+/// A snapshot of a deduplicated store, kept as `[prefix]/manifests/[snapshot].json`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A file, a symlink or an empty directory of a snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// path relative to the snapshot root encoded the same way object names are,
+    /// directories end with `/`
+    pub path: String,
+    #[serde(default)]
+    pub size: u64,
+    /// hex encoded sha256 of every chunk, in order,
+    /// chunks are stored as `[prefix]/chunks/[sha256]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// link target if the entry is a symlink stored with [SymlinkPolicy::Preserve]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// preserved file attributes, keyed the same way as in object metadata
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+enum LocalEntry {
+    File,
+    Symlink,
+    EmptyDir,
+}
+
+fn chunk_object_name(prefix: &str, hash: &str) -> String {
+    join_object_name(prefix, &format!("chunks/{}", hash))
+}
+
+fn manifest_object_name(prefix: &str, snapshot: &str) -> String {
+    join_object_name(prefix, &format!("manifests/{}.json", snapshot))
+}
+
+impl LocalSource {
+    /// Stores local file or directory as snapshot `snapshot` of a deduplicated store
+    /// under [bucket]/[prefix]
+    ///
+    /// Contents are split into content-defined chunks, a chunk already in the store
+    /// is not uploaded again no matter which file or machine it came from,
+    /// so unchanged, duplicated and renamed files take no extra space.
+    /// Snapshots are immutable, storing an existing one fails with [Error::PreconditionFailed].
+    /// Chunks can't be encrypted client-side, it fails if an encryption key is set.
+    ///
+    /// Returns uploaded chunks count
+    pub async fn to_dedup_store(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket: &str,
+        prefix: &str,
+        snapshot: &str,
+    ) -> Result<usize> {
+        if self.encryption.is_some() {
+            return Err(Error::Other {
+                message: "deduplicated stores don't support client-side encryption",
+            });
+        }
+        let path_src = path_src.as_ref();
+        let mut stored = HashSet::new();
+        let mut count = 0;
+        let mut manifest = Manifest::default();
+        for (name, path, kind) in self.scan_dedup(path_src).await? {
+            let mut entry = ManifestEntry {
+                path: name,
+                ..Default::default()
+            };
+            match kind {
+                LocalEntry::EmptyDir => {}
+                LocalEntry::Symlink => {
+                    let target = tokio::fs::read_link(&path)
+                        .await
+                        .context(Io { path: &path })?;
+                    entry.symlink = Some(self.names.encode(target.as_os_str())?.into_owned());
+                }
+                LocalEntry::File => {
+                    // read attributes before reading the content touches atime
+                    let metadata = path.metadata().context(Io { path: &path })?;
+                    PosixAttrs::from_metadata(&metadata, self.preserve)
+                        .merge_into(&mut entry.metadata);
+                    let mut reader = ChunkReader::open(&path).await?;
+                    while let Some(chunk) = reader.next().await.context(TokioIo { path: &path })? {
+                        let hash = sha256_hex(&chunk);
+                        entry.size += chunk.len() as u64;
+                        if stored.insert(hash.clone())
+                            && self.store_chunk(bucket, prefix, &hash, chunk).await?
+                        {
+                            count += 1;
+                        }
+                        entry.chunks.push(hash);
+                    }
+                }
+            }
+            manifest.entries.push(entry);
+        }
+
+        let name = manifest_object_name(prefix, snapshot);
+        log::trace!("Storing manifest gs://{}/{}", bucket, name);
+        let content = Bytes::from(serde_json::to_vec(&manifest).context(InvalidManifest {
+            object: name.clone(),
+        })?);
+        let length = content.len() as u64;
+        let new_object = NewObject {
+            name: name.clone(),
+            content_type: mime::APPLICATION_JSON.essence_str().to_owned(),
+            ..Default::default()
+        };
+        let created = self
+            .client
+            .upload(
+                bucket,
+                &new_object,
+                futures::stream::once(async move { Ok(content) }),
+                length,
+                Some(0),
+                self.server_encryption.as_ref(),
+            )
+            .await;
+        check_precondition(created, bucket, &name, OpSource::CreateObject)?;
+        Ok(count)
+    }
+
+    /// Uploads a chunk unless the store already has it, returns whether it was uploaded
+    async fn store_chunk(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        hash: &str,
+        chunk: Vec<u8>,
+    ) -> Result<bool> {
+        let name = chunk_object_name(prefix, hash);
+        let stored = self
+            .client
+            .get(bucket, &name, None, self.server_encryption.as_ref())
+            .await;
+        match stored {
+            Ok(_) => {
+                log::trace!("Skip stored chunk {}", hash);
+                return Ok(false);
+            }
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => {
+                return Err(e).context(CloudStorage {
+                    object: name,
+                    op: OpSource::ReadObject,
+                })
+            }
+        }
+
+        log::trace!("Storing chunk gs://{}/{}", bucket, name);
+        let length = chunk.len() as u64;
+        let content = Bytes::from(chunk);
+        let new_object = NewObject {
+            name: name.clone(),
+            content_type: mime::APPLICATION_OCTET_STREAM.essence_str().to_owned(),
+            ..Default::default()
+        };
+        let created = self
+            .client
+            .upload(
+                bucket,
+                &new_object,
+                futures::stream::once(async move { Ok(content) }),
+                length,
+                Some(0),
+                self.server_encryption.as_ref(),
+            )
+            .await;
+        match created {
+            // stored concurrently by someone else
+            Err(e) if is_precondition_failure(&e) => Ok(false),
+            created => {
+                created.context(CloudStorage {
+                    object: name,
+                    op: OpSource::CreateObject,
+                })?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Entries to store keyed by their manifest path, ordered by it
+    async fn scan_dedup(&self, path_src: &Path) -> Result<Vec<(String, PathBuf, LocalEntry)>> {
+        let mut found = vec![];
+        if !FileUtil::is_dir(path_src).await {
+            let filename = path_src.file_name().ok_or(Error::Other {
+                message: "path_src is not a file, should never happen, please report an issue",
+            })?;
+            let name = self.names.encode(filename)?.into_owned();
+            found.push((name, path_src.to_owned(), LocalEntry::File));
+            return Ok(found);
+        }
+
+        let canonical = tokio::fs::canonicalize(path_src)
+            .await
+            .context(Io { path: path_src })?;
+        let mut dirs = vec![(path_src.to_owned(), String::new(), vec![canonical])];
+        while let Some((dir, dir_name, ancestors)) = dirs.pop() {
+            let mut empty = true;
+            let mut entries = tokio::fs::read_dir(&dir).await.context(Io { path: &dir })?;
+            while let Some(entry) = entries.next_entry().await.context(Io { path: &dir })? {
+                empty = false;
+                let path = entry.path();
+                let name = join_object_name(&dir_name, &self.names.encode(&entry.file_name())?);
+                let file_type = entry.file_type().await.context(Io { path: &path })?;
+                if file_type.is_symlink() {
+                    match self.symlinks {
+                        SymlinkPolicy::Skip => {
+                            log::trace!("Skip symlink {:?}", path);
+                            continue;
+                        }
+                        SymlinkPolicy::Preserve => {
+                            found.push((name, path, LocalEntry::Symlink));
+                            continue;
+                        }
+                        SymlinkPolicy::Follow => {}
+                    }
+                }
+                if FileUtil::is_dir(&path).await {
+                    let canonical = tokio::fs::canonicalize(&path)
+                        .await
+                        .context(Io { path: &path })?;
+                    if ancestors.contains(&canonical) {
+                        log::warn!("Skip {:?}, symlink cycle back to {:?}", path, canonical);
+                        continue;
+                    }
+                    let mut ancestors = ancestors.clone();
+                    ancestors.push(canonical);
+                    dirs.push((path, name, ancestors));
+                } else {
+                    found.push((name, path, LocalEntry::File));
+                }
+            }
+            if empty && !dir_name.is_empty() {
+                found.push((format!("{}/", dir_name), dir, LocalEntry::EmptyDir));
+            }
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+}
+
+impl GcsSource {
+    /// Reads manifest of snapshot `snapshot` of a deduplicated store under [bucket]/[prefix]
+    pub async fn dedup_manifest(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        snapshot: &str,
+    ) -> Result<Manifest> {
+        let name = manifest_object_name(prefix, snapshot);
+        let content = self.download_bytes(bucket, &name).await?;
+        serde_json::from_slice(&content).context(InvalidManifest { object: name })
+    }
+
+    /// Restores snapshot `snapshot` of a deduplicated store under [bucket]/[prefix]
+    /// to a local directory, reassembling files from their chunks
+    ///
+    /// Returns restored files count
+    pub async fn restore_dedup_snapshot(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        snapshot: &str,
+        dst_dir: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let dst_dir = dst_dir.as_ref();
+        let manifest = self.dedup_manifest(bucket, prefix, snapshot).await?;
+        let mut names = LocalNames::default();
+        let mut count = 0;
+        for entry in &manifest.entries {
+            let is_dir = entry.path.ends_with('/');
+            let relative = match names.claim(
+                &entry.path,
+                local_relative_path(self.names, &entry.path),
+                is_dir,
+            ) {
+                Some(relative) => relative,
+                None => continue,
+            };
+            let path_dst = dst_dir.join(relative);
+            Self::create_parent_dirs(self.force_overwrite, dst_dir, &path_dst).await?;
+
+            if is_dir {
+                Self::maybe_create_dir(self.force_overwrite, &path_dst).await?;
+                continue;
+            }
+            match (self.symlinks, &entry.symlink) {
+                (SymlinkPolicy::Skip, Some(_)) => {
+                    log::trace!("Skip symlink {:?}", entry.path);
+                    continue;
+                }
+                (SymlinkPolicy::Preserve, Some(target)) => {
+                    let target = self.names.decode(target)?;
+                    count +=
+                        Self::restore_symlink(self.force_overwrite, dst_dir, &path_dst, &target)?;
+                    continue;
+                }
+                // the same content a symlink object has
+                (SymlinkPolicy::Follow, Some(target)) => {
+                    Self::remove_symlink(self.force_overwrite, &path_dst).await?;
+                    tokio::fs::write(&path_dst, target)
+                        .await
+                        .context(Io { path: &path_dst })?;
+                    count += 1;
+                    continue;
+                }
+                (_, None) => {}
+            }
+
+            Self::remove_symlink(self.force_overwrite, &path_dst).await?;
+            if self.should_restore(entry, &path_dst).await? {
+                self.restore_chunks(bucket, prefix, entry, &path_dst)
+                    .await?;
+                count += 1;
+            } else {
+                log::trace!("Skip {:?}", path_dst);
+            }
+            // attributes are restored even if the content is up to date
+            if self.preserve.any() {
+                PosixAttrs::from_object_metadata(Some(&entry.metadata), self.preserve)
+                    .apply(&path_dst)?;
+            }
+        }
+
+        if names.rejected.is_empty() {
+            Ok(count)
+        } else {
+            Err(Error::UnrepresentableNames {
+                names: names.rejected,
+            })
+        }
+    }
+
+    async fn should_restore(&self, entry: &ManifestEntry, path_dst: &Path) -> Result<bool> {
+        if self.force_overwrite {
+            return Ok(true);
+        }
+        match tokio::fs::symlink_metadata(path_dst).await {
+            Ok(metadata) if metadata.is_file() && metadata.len() == entry.size => {
+                Ok(file_chunks(path_dst).await? != entry.chunks)
+            }
+            Ok(metadata) if metadata.is_file() => Ok(true),
+            Ok(_) => Err(Error::AlreadyExists {
+                path: path_dst.to_owned(),
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(true),
+            Err(err) => Err(err).context(Io { path: path_dst }),
+        }
+    }
+
+    async fn restore_chunks(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        entry: &ManifestEntry,
+        path_dst: &Path,
+    ) -> Result<()> {
+        log::trace!(
+            "Restoring {:?} from {} chunks",
+            path_dst,
+            entry.chunks.len()
+        );
+        if self.preserve.mode {
+            make_writable(path_dst)?;
+        }
+        let mut file_dst = File::create(path_dst)
+            .await
+            .context(TokioIo { path: path_dst })?;
+        for hash in &entry.chunks {
+            let name = chunk_object_name(prefix, hash);
+            let chunk = self.download_bytes(bucket, &name).await?;
+            if sha256_hex(&chunk) != *hash {
+                return Err(Error::CorruptChunk { object: name });
+            }
+            file_dst
+                .write_all(&chunk)
+                .await
+                .context(TokioIo { path: path_dst })?;
+        }
+        file_dst
+            .sync_all()
+            .await
+            .context(TokioIo { path: path_dst })?;
+        Ok(())
+    }
+
+    /// Downloads the whole content of a small object
+    async fn download_bytes(&self, bucket: &str, name: &str) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .download(bucket, name, None, self.server_encryption.as_ref())
+            .await
+            .context(CloudStorage {
+                object: name.to_owned(),
+                op: OpSource::ReadObject,
+            })?;
+        response
+            .bytes_stream()
+            .map_err(Error::from)
+            .try_fold(vec![], |mut content, chunk| async move {
+                content.extend_from_slice(&chunk);
+                Ok(content)
+            })
+            .await
+    }
+}
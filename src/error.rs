@@ -5,6 +5,8 @@ use std::path::PathBuf;
 pub enum OpSource {
     CreateObject,
     CopyObject,
+    UpdateObject,
+    DeleteObject,
     ReadObject,
     DownloadUrl,
     ListPrefix,
@@ -17,6 +19,33 @@ impl OpSource {
     }
 }
 
+/// Why an object cannot be downloaded to a local path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameProblem {
+    /// The name starts with `/`
+    Absolute,
+    /// The name contains a `..` segment
+    ParentTraversal,
+    /// A segment can't be used as a local file name
+    InvalidSegment { segment: String },
+    /// The name is empty after normalization
+    Empty,
+    /// Another object maps to the same local path, or one of them needs it to be a directory
+    Collision { with: String },
+}
+
+impl std::fmt::Display for NameProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Absolute => write!(f, "absolute path"),
+            Self::ParentTraversal => write!(f, "contains \"..\" segment"),
+            Self::InvalidSegment { segment } => write!(f, "invalid segment {:?}", segment),
+            Self::Empty => write!(f, "empty name"),
+            Self::Collision { with } => write!(f, "collides with {:?}", with),
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub enum Error {
@@ -48,7 +77,122 @@ pub enum Error {
     WrongPath {
         path: PathBuf,
     },
+    #[snafu(display(
+        "{} objects can't be represented locally: {}",
+        names.len(),
+        names
+            .iter()
+            .map(|(name, problem)| format!("{:?} ({})", name, problem))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]
+    UnrepresentableNames {
+        names: Vec<(String, NameProblem)>,
+    },
+    #[snafu(display("gs://{}/{} was changed by someone else during sync", bucket, object))]
+    PreconditionFailed {
+        bucket: String,
+        object: String,
+    },
+    #[snafu(display("Generation {} of {} not found", generation, object))]
+    GenerationNotFound {
+        object: String,
+        generation: i64,
+    },
+    #[snafu(display("Files changed on both sides: {}", paths.join(", ")))]
+    Conflicts {
+        paths: Vec<String>,
+    },
+    #[snafu(display("Invalid sync state file {:?}: {}", path, source))]
+    SyncState {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Invalid manifest {}: {}", object, source))]
+    InvalidManifest {
+        object: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Chunk {} content doesn't match its hash", object))]
+    CorruptChunk {
+        object: String,
+    },
+    #[snafu(display("Can't decrypt {}: {}", object, reason))]
+    Decryption {
+        object: String,
+        reason: &'static str,
+    },
+    #[snafu(display("Can't decompress {}: {}", object, source))]
+    Decompression {
+        object: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Invalid glob {:?}: {}", pattern, source))]
+    InvalidGlob {
+        pattern: String,
+        source: globset::Error,
+    },
+    #[snafu(display("Invalid notification, missing or malformed {}", reason))]
+    InvalidNotification {
+        reason: &'static str,
+    },
+    #[snafu(display("Invalid service account key: {}", source))]
+    InvalidServiceAccount {
+        source: serde_json::Error,
+    },
+    #[snafu(display("Invalid job file: {}", source))]
+    JobFile {
+        source: toml::de::Error,
+    },
+    #[snafu(display("Invalid job file, {}: {}", field, reason))]
+    InvalidJob {
+        field: String,
+        reason: String,
+    },
+    #[snafu(display(
+        "Invalid location {:?}, expected a local path or gs://bucket/prefix",
+        location
+    ))]
+    InvalidLocation {
+        location: String,
+    },
+    #[snafu(display("Path is not valid UTF-8: {:?}", path))]
+    NonUtf8Path {
+        path: PathBuf,
+    },
     AlreadyExists {
         path: PathBuf,
     },
+    #[snafu(display("Symlink {:?} -> {:?} leads outside of the destination", path, target))]
+    UnsafeSymlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    #[snafu(display("Can't write {:?}, {:?} is a symlink", path, symlink))]
+    SymlinkInPath {
+        path: PathBuf,
+        symlink: PathBuf,
+    },
+}
+
+impl Error {
+    /// Whether the error may go away if the operation is repeated:
+    /// network failures, rate limiting and server errors
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::CloudStorage { source, .. } => match source.as_ref() {
+                cloud_storage::Error::Google(response) => {
+                    response.error.code == 429 || response.error.code >= 500
+                }
+                cloud_storage::Error::Reqwest(e) => is_transient_reqwest(e),
+                _ => false,
+            },
+            Self::Reqwest { source } => is_transient_reqwest(source),
+            _ => false,
+        }
+    }
+}
+
+fn is_transient_reqwest(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
 }
@@ -1,37 +1,206 @@
+use crate::api::*;
+use crate::auth::Credentials;
+use crate::compression::*;
+use crate::crypto::*;
 use crate::error::*;
+use crate::local::{Comparison, SymlinkPolicy, SYMLINK_TARGET_KEY};
+use crate::names::*;
+use crate::posix::*;
+use crate::rules::*;
+use crate::sync::{report, Progress, SyncEvent};
 use crate::util::*;
+use crate::versions::Versions;
 use crate::Result;
-use cloud_storage::{object::Object, Client, ListRequest};
-use futures::stream::FuturesUnordered;
+use bytes::Bytes;
+use cloud_storage::{object::Object, ListRequest};
+use futures::future;
+use futures::stream::{self, BoxStream, FuturesUnordered};
 use futures::stream::{StreamExt, TryStreamExt};
 use snafu::{futures::TryStreamExt as SnafuTryStreamExt, ResultExt};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
 
+/// Generation and metageneration of listed objects by name
+type Generations = HashMap<String, (i64, i64)>;
+
 #[derive(Debug)]
 pub struct GcsSource {
     pub(crate) force_overwrite: bool,
     pub(crate) concurrency: usize,
     pub(crate) client: Client,
+    pub(crate) preserve: Preserve,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) names: NameEncoding,
+    pub(crate) versions: Versions,
+    pub(crate) encryption: Option<EncryptionKey>,
+    pub(crate) server_encryption: Option<ServerEncryption>,
+    pub(crate) decompress: bool,
+    pub(crate) metadata_rules: Vec<MetadataRule>,
+    pub(crate) object_defaults: ObjectProperties,
+    pub(crate) filter: Filter,
+    pub(crate) dry_run: bool,
+    pub(crate) comparison: Comparison,
+    pub(crate) progress: Option<Progress>,
 }
 
 impl GcsSource {
     pub fn new(force_overwrite: bool, concurrency: usize) -> Self {
-        let client = Client::default();
         Self {
             force_overwrite,
             concurrency,
-            client,
+            client: Client::default(),
+            preserve: Preserve::default(),
+            symlinks: SymlinkPolicy::default(),
+            names: NameEncoding::default(),
+            versions: Versions::default(),
+            encryption: None,
+            server_encryption: None,
+            decompress: false,
+            metadata_rules: vec![],
+            object_defaults: ObjectProperties::default(),
+            filter: Filter::default(),
+            dry_run: false,
+            comparison: Comparison::default(),
+            progress: None,
         }
     }
 
+    /// Decrypts content of objects encrypted on upload with the same key,
+    /// objects stored without encryption are downloaded as they are
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Sends a customer-supplied key with listings, downloads and copies,
+    /// objects copied within Cloud Storage are encrypted with the same key
+    pub fn with_server_encryption(mut self, encryption: ServerEncryption) -> Self {
+        self.server_encryption = Some(encryption);
+        self
+    }
+
+    /// Decompresses content stored with `Content-Encoding: gzip` on download,
+    /// such objects are downloaded as they are stored by default
+    pub fn with_gzip_decompression(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// Sets properties of objects copied by [GcsSource::to_gcs] matching the rule,
+    /// globs are matched against names relative to the destination prefix
+    pub fn with_metadata_rule(mut self, rule: MetadataRule) -> Self {
+        self.metadata_rules.push(rule);
+        self
+    }
+
+    /// Writes copies with a storage class unless a metadata rule sets another one
+    pub fn with_storage_class(mut self, storage_class: StorageClass) -> Self {
+        self.object_defaults.storage_class = Some(storage_class);
+        self
+    }
+
+    /// Places or releases a temporary hold on copies unless a metadata rule overrides it
+    pub fn with_temporary_hold(mut self, hold: bool) -> Self {
+        self.object_defaults.temporary_hold = Some(hold);
+        self
+    }
+
+    /// Places or releases an event-based hold on copies unless a metadata rule overrides it
+    pub fn with_event_based_hold(mut self, hold: bool) -> Self {
+        self.object_defaults.event_based_hold = Some(hold);
+        self
+    }
+
+    /// Selects object generations to sync from a versioned bucket,
+    /// e.g. [Versions::AsOf] restores a prefix as it was at some point in time.
+    /// Selected generations are downloaded over the destination, local files without
+    /// a selected generation are never deleted. Only live objects are synced by default
+    pub fn with_versions(mut self, versions: Versions) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Sets how object names are converted to local file names,
+    /// should match the encoding used on upload, [NameEncoding::Utf8] by default
+    pub fn with_name_encoding(mut self, names: NameEncoding) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Sets how objects uploaded with [SymlinkPolicy::Preserve] are handled:
+    /// `Preserve` recreates the links, `Skip` ignores them
+    /// and `Follow` (the default) downloads them as regular files containing the link target
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Restores file modification and access times from `goog-reserved-file-mtime`
+    /// and `goog-reserved-file-atime` object metadata, if present
+    pub fn with_preserve_times(mut self, preserve_times: bool) -> Self {
+        self.preserve.times = preserve_times;
+        self
+    }
+
+    /// Restores file permissions from `goog-reserved-posix-mode` object metadata, if present,
+    /// setuid, setgid and sticky bits are never restored
+    pub fn with_preserve_permissions(mut self, preserve_permissions: bool) -> Self {
+        self.preserve.mode = preserve_permissions;
+        self
+    }
+
+    /// Syncs only objects passing the filter,
+    /// names are matched relative to the synced prefix
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Authorizes requests with the credentials instead of the service account key
+    /// at the path in `SERVICE_ACCOUNT`
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.client = self.client.with_credentials(credentials);
+        self
+    }
+
+    /// Bills requests to the project, required to sync with requester-pays buckets
+    pub fn with_billing_project(mut self, project: impl Into<String>) -> Self {
+        self.client = self.client.with_billing_project(project);
+        self
+    }
+
+    /// Counts and logs downloads and copies a sync would make without making them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets how objects are compared to existing files, [Comparison::Checksum] by default
+    pub fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparison = comparison;
+        self
+    }
+
+    /// Sends requests through `client`, sharing its connections and tokens
+    /// with other sources
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Client requests are sent through
     pub fn client(&self) -> &Client {
         &self.client
     }
 
     /// Syncs remote Gcs bucket path to a local path
     ///
+    /// Objects under `path_src/` are synced into `dst_dir`. An object named `path_src`
+    /// itself is synced to the file `dst_dir`, or into it if it's a directory
+    /// or ends with a separator.
     /// Returns actual downloads count
     pub async fn to_local(
         &self,
@@ -45,37 +214,79 @@ impl GcsSource {
             path_src,
             dst_dir.as_ref()
         );
+        let (count, names, _) = self
+            .sync_to_local(bucket_src, path_src, dst_dir.as_ref(), &Generations::new())
+            .await?;
+
+        if names.rejected.is_empty() {
+            Ok(count)
+        } else {
+            Err(Error::UnrepresentableNames {
+                names: names.rejected,
+            })
+        }
+    }
+
+    /// Keeps a local path following a remote Gcs bucket path
+    ///
+    /// Syncs like [GcsSource::to_local], then lists the path every `interval`
+    /// and downloads only objects with a generation or metageneration not seen
+    /// by the previous listing. Local files of removed objects are kept.
+    /// Failures of later polls are logged and retried on the next one,
+    /// drop the future to stop following
+    pub async fn follow(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        dst_dir: impl AsRef<Path>,
+        interval: Duration,
+    ) -> Result<()> {
         let dst_dir = dst_dir.as_ref();
+        let (count, _, mut known) = self
+            .sync_to_local(bucket_src, path_src, dst_dir, &Generations::new())
+            .await?;
+        log::trace!("Synced {:?} before following, {} downloads", dst_dir, count);
+        loop {
+            tokio::time::sleep(interval).await;
+            match self
+                .sync_to_local(bucket_src, path_src, dst_dir, &known)
+                .await
+            {
+                Ok((count, _, listed)) => {
+                    log::trace!(
+                        "Polled gs://{}/{}, {} downloads",
+                        bucket_src,
+                        path_src,
+                        count
+                    );
+                    known = listed;
+                }
+                Err(e) => log::error!("Can't sync gs://{}/{}: {}", bucket_src, path_src, e),
+            }
+        }
+    }
+
+    /// Syncs objects listed under a remote path, skipping the ones `known` at the same
+    /// generation and metageneration. Returns downloads count, names of synced objects
+    /// and generations of all listed objects
+    async fn sync_to_local(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        dst_dir: &Path,
+        known: &Generations,
+    ) -> Result<(usize, LocalNames, Generations)> {
         log::trace!("Requesting objects");
-        let objects_src = self
-            .client
-            .object()
-            .list(
-                bucket_src,
-                ListRequest {
-                    prefix: Some(path_src.to_owned()),
-                    ..Default::default()
-                },
-            )
-            .await
-            .context(CloudStorage {
-                object: path_src.to_owned(),
-                op: OpSource::pre(OpSource::ListPrefix),
-            })?;
+        let objects_src = self.list_pages(bucket_src, path_src).await?;
         log::trace!("iterating objects");
-        objects_src
-            .context(CloudStorage {
-                object: path_src.to_owned(),
-                op: OpSource::ListPrefix,
-            })
-            // .map_err(Error::from)
+        let (count, _, names, listed) = objects_src
             .try_fold(
-                (0usize, dst_dir),
-                |(mut count, dst_dir), object_srcs| async move {
+                (0usize, dst_dir, LocalNames::default(), Generations::new()),
+                |(mut count, dst_dir, mut names, mut listed), object_srcs| async move {
                     log::trace!("objects: {:?}", object_srcs);
                     let mut jobs_pool = FuturesUnordered::new();
 
-                    for object_src in object_srcs.items {
+                    for object_src in object_srcs {
                         log::trace!("object: {:?}", object_src);
 
                         if jobs_pool.len() == self.concurrency {
@@ -83,39 +294,65 @@ impl GcsSource {
                             count += jobs_pool.next().await.unwrap()?;
                         }
 
-                        let strip_prefix = if path_src.ends_with('/') {
-                            path_src.to_owned()
+                        let is_dir = object_src.name.ends_with('/');
+                        let single = object_src.name == path_src && !is_dir;
+                        let path_dst = if single {
+                            match self.single_object_path(&object_src.name, dst_dir)? {
+                                Some(path_dst) => path_dst,
+                                None => continue,
+                            }
                         } else {
-                            format!("{}/", path_src)
-                        };
-                        let stripped_object_name =
-                            object_src.name.strip_prefix(&strip_prefix).ok_or({
-                                Error::Other {
-                message: "Failed to strip path prefix, should never happen, please report an issue",
-            }
-                            })?;
-                        let path_dst = dst_dir.join(stripped_object_name);
-
-                        Self::create_parent_dirs(self.force_overwrite, &path_dst).await?;
-
-                        if object_src.name.ends_with('/') {
-                            let created =
-                                Self::maybe_create_dir(self.force_overwrite, &path_dst).await?;
-                            if let Some(created) = created {
-                                log::trace!("Created dir {:?}", created.as_os_str());
+                            let stripped_object_name = match object_src
+                                .name
+                                .strip_prefix(&join_object_name(path_src, ""))
+                            {
+                                Some(stripped) => stripped,
+                                None => {
+                                    // shares the prefix only, like `a.csv.bak` of `a.csv`
+                                    log::trace!(
+                                        "Skip {:?}, not in {:?}",
+                                        object_src.name,
+                                        path_src
+                                    );
+                                    continue;
+                                }
+                            };
+                            if self.filter.excludes(stripped_object_name) {
+                                log::trace!("Skip filtered {:?}", object_src.name);
+                                continue;
+                            }
+                            match names.claim(
+                                &object_src.name,
+                                local_relative_path(self.names, stripped_object_name),
+                                is_dir,
+                            ) {
+                                Some(relative) => dst_dir.join(relative),
+                                None => continue,
                             }
+                        };
+                        let generation = (object_src.generation, object_src.metageneration);
+                        listed.insert(object_src.name.clone(), generation);
+                        if known.get(&object_src.name) == Some(&generation) {
+                            log::trace!("Skip {:?}, unchanged", object_src.name);
                             continue;
                         }
 
-                        let path_dst = path_dst.to_str().expect("valid utf8 file name").to_owned();
+                        let root = match path_dst.parent() {
+                            Some(parent) if single => parent,
+                            _ => dst_dir,
+                        };
+                        if let Some(restored) =
+                            self.restore_entry(&object_src, root, &path_dst).await?
+                        {
+                            count += restored;
+                            continue;
+                        }
 
                         log::trace!("downloading object {:?}", object_src);
-                        let job = Self::download_object(
-                            self.force_overwrite,
-                            bucket_src,
-                            path_dst,
-                            object_src,
-                        );
+                        let job = async move {
+                            self.download_object(bucket_src, path_dst, &object_src)
+                                .await
+                        };
 
                         jobs_pool.push(job);
                     }
@@ -126,11 +363,109 @@ impl GcsSource {
                     }
                     log::trace!("all jobs completed");
 
-                    Ok((count, dst_dir))
+                    Ok((count, dst_dir, names, listed))
                 },
             )
-            .await
-            .map(|(count, _)| count)
+            .await?;
+
+        Ok((count, names, listed))
+    }
+
+    /// Local path of the object named by `path_src` itself: `dst` if it's a file path,
+    /// a file in `dst` if it's a directory or ends with a separator.
+    /// Returns `None` if the object is filtered out
+    fn single_object_path(&self, name: &str, dst: &Path) -> Result<Option<PathBuf>> {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        if self.filter.excludes(file_name) {
+            log::trace!("Skip filtered {:?}", name);
+            return Ok(None);
+        }
+        let into_dir = dst.is_dir()
+            || dst
+                .as_os_str()
+                .to_string_lossy()
+                .ends_with(std::path::is_separator);
+        if !into_dir {
+            return Ok(Some(dst.to_owned()));
+        }
+        match local_relative_path(self.names, file_name) {
+            Ok(relative) => Ok(Some(dst.join(relative))),
+            Err(problem) => Err(Error::UnrepresentableNames {
+                names: vec![(name.to_owned(), problem)],
+            }),
+        }
+    }
+
+    /// Creates parent directories of an object's local path under `root`, then creates
+    /// a directory or a symlink for objects that are not downloaded as files.
+    /// Returns `None` if the object's content has to be downloaded
+    pub(crate) async fn restore_entry(
+        &self,
+        object_src: &Object,
+        root: &Path,
+        path_dst: &Path,
+    ) -> Result<Option<usize>> {
+        if self.dry_run {
+            return self.would_restore_entry(object_src, root, path_dst);
+        }
+        Self::create_parent_dirs(self.force_overwrite, root, path_dst).await?;
+
+        if object_src.name.ends_with('/') {
+            let created = Self::maybe_create_dir(self.force_overwrite, path_dst).await?;
+            if let Some(created) = created {
+                log::trace!("Created dir {:?}", created.as_os_str());
+            }
+            return Ok(Some(0));
+        }
+
+        let symlink_target = object_src
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(SYMLINK_TARGET_KEY));
+        match (self.symlinks, symlink_target) {
+            (SymlinkPolicy::Skip, Some(_)) => {
+                log::trace!("Skip symlink {:?}", object_src.name);
+                Ok(Some(0))
+            }
+            (SymlinkPolicy::Preserve, Some(target)) => {
+                let target = self.names.decode(target)?;
+                Self::restore_symlink(self.force_overwrite, root, path_dst, &target).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// [GcsSource::restore_entry] of a dry run
+    fn would_restore_entry(
+        &self,
+        object_src: &Object,
+        root: &Path,
+        path_dst: &Path,
+    ) -> Result<Option<usize>> {
+        if object_src.name.ends_with('/') {
+            if !path_dst.is_dir() {
+                log::info!("Would create {:?}", path_dst);
+            }
+            // created directories aren't counted by a sync either
+            return Ok(Some(0));
+        }
+        let symlink_target = object_src
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(SYMLINK_TARGET_KEY));
+        let restored = match (self.symlinks, symlink_target) {
+            (SymlinkPolicy::Skip, Some(_)) => false,
+            (SymlinkPolicy::Preserve, Some(target)) => {
+                let target = self.names.decode(target)?;
+                check_symlink_target(root, path_dst, &target)?;
+                std::fs::read_link(path_dst).ok().as_deref() != Some(target.as_ref())
+            }
+            _ => return Ok(None),
+        };
+        if restored {
+            log::info!("Would create {:?}", path_dst);
+        }
+        Ok(Some(restored as usize))
     }
 
     /// Copies remote Gcs bucket file or directory to another remote Gcs bucket file or directory
@@ -141,38 +476,88 @@ impl GcsSource {
         bucket_dst: &str,
         path_dst: &str,
     ) -> Result<usize, Error> {
-        let objects_src = self
-            .client
-            .object()
-            .list(
-                bucket_src,
-                ListRequest {
-                    prefix: Some(path_src.to_owned()),
-                    ..Default::default()
-                },
-            )
-            .await
-            .context(CloudStorage {
-                object: path_src.to_owned(),
-                op: OpSource::pre(OpSource::ListPrefix),
-            })?;
+        let objects_src = self.list_pages(bucket_src, path_src).await?;
         objects_src
-            .context(CloudStorage {
-                object: path_src.to_owned(),
-                op: OpSource::ListPrefix,
-            })
-            // .map_err(Error::from)
             .try_fold(
                 (0usize, bucket_dst, path_dst),
                 |(mut count, bucket_dst, path_dst), object_srcs| async move {
-                    for object_src in object_srcs.items {
-                        object_src
-                            .copy(bucket_dst, path_dst)
+                    for object_src in object_srcs {
+                        let name_dst = match copy_destination(path_src, path_dst, &object_src.name)
+                        {
+                            Some(name_dst) => name_dst,
+                            None => {
+                                log::trace!("Skip {:?} outside of {:?}", object_src.name, path_src);
+                                continue;
+                            }
+                        };
+                        let root = join_object_name(path_dst, "");
+                        let relative = name_dst.strip_prefix(&root).unwrap_or(&name_dst);
+                        if self.filter.excludes(relative) {
+                            log::trace!("Skip filtered {:?}", object_src.name);
+                            continue;
+                        }
+                        if self.dry_run {
+                            log::info!(
+                                "Would copy gs://{}/{} to gs://{}/{}",
+                                bucket_src,
+                                object_src.name,
+                                bucket_dst,
+                                name_dst
+                            );
+                            count += 1;
+                            continue;
+                        }
+                        log::trace!(
+                            "Copy gs://{}/{}#{} to gs://{}/{}",
+                            bucket_src,
+                            object_src.name,
+                            object_src.generation,
+                            bucket_dst,
+                            name_dst
+                        );
+                        let properties = ObjectProperties::resolve(
+                            &self.object_defaults,
+                            &self.metadata_rules,
+                            relative,
+                        );
+                        let copied = self
+                            .client
+                            .rewrite(
+                                &object_src,
+                                bucket_dst,
+                                &name_dst,
+                                properties.storage_class,
+                                None,
+                                self.server_encryption.as_ref(),
+                            )
                             .await
                             .context(CloudStorage {
-                                object: path_dst.to_owned(),
+                                object: name_dst.clone(),
                                 op: OpSource::CopyObject,
                             })?;
+                        if !properties.stored_in(&copied) {
+                            let patched = self
+                                .client
+                                .patch(
+                                    bucket_dst,
+                                    &name_dst,
+                                    &properties,
+                                    Some(copied.metageneration),
+                                )
+                                .await;
+                            check_precondition(
+                                patched,
+                                bucket_dst,
+                                &name_dst,
+                                OpSource::UpdateObject,
+                            )?;
+                        }
+                        report(self.progress.as_ref(), || SyncEvent::Copied {
+                            bucket: bucket_src.to_owned(),
+                            object: object_src.name.clone(),
+                            bucket_dst: bucket_dst.to_owned(),
+                            object_dst: name_dst,
+                        });
                         count += 1;
                     }
 
@@ -183,8 +568,88 @@ impl GcsSource {
             .map(|(count, ..)| count)
     }
 
-    async fn create_parent_dirs(force_overwrite: bool, path_dst: impl AsRef<Path>) -> Result<()> {
+    /// Lists objects under a prefix page by page,
+    /// or all at once when generations have to be selected from object history
+    async fn list_pages<'a>(
+        &'a self,
+        bucket: &'a str,
+        prefix: &'a str,
+    ) -> Result<BoxStream<'a, Result<Vec<Object>>>> {
+        if self.versions.needs_history() {
+            let objects = self.list_objects(bucket, prefix, true).await?;
+            let selected = self.versions.select(prefix, objects)?;
+            return Ok(stream::once(future::ready(Ok(selected))).boxed());
+        }
+
+        let request = ListRequest {
+            prefix: Some(prefix.to_owned()),
+            ..Default::default()
+        };
+        Ok(self
+            .client
+            .list(bucket, request, self.server_encryption.as_ref())
+            .context(CloudStorage {
+                object: prefix.to_owned(),
+                op: OpSource::ListPrefix,
+            })
+            .map_ok(|list| list.items)
+            .boxed())
+    }
+
+    /// Lists all objects under a prefix, including noncurrent generations if `versions` is set
+    pub(crate) async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        versions: bool,
+    ) -> Result<Vec<Object>> {
+        let request = ListRequest {
+            prefix: Some(prefix.to_owned()),
+            versions: Some(versions),
+            ..Default::default()
+        };
+        self.client
+            .list(bucket, request, self.server_encryption.as_ref())
+            .context(CloudStorage {
+                object: prefix.to_owned(),
+                op: OpSource::ListPrefix,
+            })
+            .try_fold(vec![], |mut all, list| async move {
+                all.extend(list.items);
+                Ok(all)
+            })
+            .await
+    }
+
+    /// Lists "directories" directly under a prefix ending with `/`
+    pub(crate) async fn list_prefixes(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        let request = ListRequest {
+            prefix: Some(prefix.to_owned()),
+            delimiter: Some("/".to_owned()),
+            ..Default::default()
+        };
+        self.client
+            .list(bucket, request, self.server_encryption.as_ref())
+            .context(CloudStorage {
+                object: prefix.to_owned(),
+                op: OpSource::ListPrefix,
+            })
+            .try_fold(vec![], |mut all, list| async move {
+                all.extend(list.prefixes);
+                Ok(all)
+            })
+            .await
+    }
+
+    /// Creates missing directories between `root` and `path_dst`,
+    /// failing if one of the existing ones is a symlink
+    pub(crate) async fn create_parent_dirs(
+        force_overwrite: bool,
+        root: &Path,
+        path_dst: impl AsRef<Path>,
+    ) -> Result<()> {
         let path_dst = PathBuf::from(path_dst.as_ref());
+        check_no_symlink_parents(root, &path_dst)?;
 
         if let Some(dir_dst) = path_dst.parent() {
             if FileUtil::exists(dir_dst).await {
@@ -208,7 +673,7 @@ impl GcsSource {
         Ok(())
     }
 
-    async fn maybe_create_dir(
+    pub(crate) async fn maybe_create_dir(
         force_overwrite: bool,
         path_dst: impl AsRef<Path>,
     ) -> Result<Option<PathBuf>> {
@@ -236,17 +701,61 @@ impl GcsSource {
         }
     }
 
-    async fn download_object(
+    /// Creates a symlink unless the same one already exists,
+    /// targets outside of `root` are rejected
+    pub(crate) fn restore_symlink(
         force_overwrite: bool,
+        root: &Path,
+        path_dst: impl AsRef<Path>,
+        target: &Path,
+    ) -> Result<usize> {
+        let path_dst = path_dst.as_ref();
+        check_symlink_target(root, path_dst, target)?;
+        match std::fs::symlink_metadata(path_dst) {
+            Ok(md) if md.file_type().is_symlink() => {
+                let existing = std::fs::read_link(path_dst).context(Io { path: path_dst })?;
+                if existing == target {
+                    log::trace!("Skip symlink {:?}", path_dst);
+                    return Ok(0);
+                }
+                std::fs::remove_file(path_dst).context(Io { path: path_dst })?;
+            }
+            Ok(md) if md.is_file() && force_overwrite => {
+                std::fs::remove_file(path_dst).context(Io { path: path_dst })?;
+            }
+            Ok(_) => {
+                return Err(Error::AlreadyExists {
+                    path: path_dst.to_owned(),
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context(Io { path: path_dst }),
+        }
+        log::trace!("Creating symlink {:?} -> {:?}", path_dst, target);
+        create_symlink(target, path_dst).context(Io { path: path_dst })?;
+        Ok(1)
+    }
+
+    pub(crate) async fn download_object(
+        &self,
         bucket_src: &str,
         path_dst: impl AsRef<Path>,
-        object_src: Object,
+        object_src: &Object,
     ) -> Result<usize> {
         let mut count = 0;
         let path_dst = path_dst.as_ref();
+        let preserve = self.preserve;
 
-        if !Self::should_download(force_overwrite, &object_src, path_dst).await? {
+        if !self.should_download(object_src, path_dst).await? {
             log::trace!("Skip {:?}", object_src.name);
+        } else if self.dry_run {
+            log::info!(
+                "Would copy gs://{}/{} to {:?}",
+                bucket_src,
+                object_src.name,
+                path_dst
+            );
+            return Ok(1);
         } else {
             log::trace!(
                 "Copy gs://{}/{} to {:?}",
@@ -254,43 +763,181 @@ impl GcsSource {
                 object_src.name,
                 &path_dst,
             );
-            let file_dst = File::create(path_dst)
+            let decryptor = self.decryptor(object_src)?;
+            let gunzip = self.gunzip(object_src);
+            Self::remove_symlink(self.force_overwrite, path_dst).await?;
+            // complete and authenticated content only replaces the destination
+            let mut partial = PartialFile::create(path_dst)
                 .await
                 .context(Io { path: path_dst })?;
+            let copied = match self
+                .write_object(
+                    bucket_src,
+                    object_src,
+                    path_dst,
+                    &mut partial.file,
+                    decryptor,
+                    gunzip,
+                )
+                .await
+            {
+                Ok(copied) => copied,
+                Err(e) => {
+                    partial.remove().await;
+                    return Err(e);
+                }
+            };
+            partial
+                .persist(path_dst)
+                .await
+                .context(Io { path: path_dst })?;
+            count += 1;
+            report(self.progress.as_ref(), || SyncEvent::Downloaded {
+                bucket: bucket_src.to_owned(),
+                object: object_src.name.clone(),
+                path: path_dst.to_owned(),
+                bytes: copied as u64,
+            });
 
-            let url_src = object_src.download_url(60).context(CloudStorage {
+            log::trace!("Copied {} bytes", copied);
+        }
+
+        // attributes are restored even if the content is up to date
+        if preserve.any() && !self.dry_run {
+            PosixAttrs::from_object_metadata(object_src.metadata.as_ref(), preserve)
+                .apply(path_dst)?;
+        }
+        Ok(count)
+    }
+
+    /// Removes a symlink at a path about to be written if overwriting is forced,
+    /// a restored symlink must not redirect the content elsewhere
+    pub(crate) async fn remove_symlink(force_overwrite: bool, path_dst: &Path) -> Result<()> {
+        let is_symlink = fs::symlink_metadata(path_dst)
+            .await
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink && force_overwrite {
+            fs::remove_file(path_dst)
+                .await
+                .context(Io { path: path_dst })?;
+        } else if is_symlink {
+            return Err(Error::AlreadyExists {
+                path: path_dst.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes decrypted and decompressed content of an object to `file_dst`,
+    /// returns the count of downloaded bytes
+    async fn write_object(
+        &self,
+        bucket_src: &str,
+        object_src: &Object,
+        path_dst: &Path,
+        file_dst: &mut File,
+        decryptor: Option<Decryptor>,
+        gunzip: Option<Gunzip>,
+    ) -> Result<usize> {
+        // the listed generation, so content matches the size and checksum compared above
+        let response_src = self
+            .client
+            .download(
+                bucket_src,
+                &object_src.name,
+                Some(object_src.generation),
+                self.server_encryption.as_ref(),
+            )
+            .await
+            .context(CloudStorage {
                 object: object_src.name.to_owned(),
-                op: OpSource::DownloadUrl,
+                op: OpSource::ReadObject,
             })?;
-            let response_src = reqwest::get(&url_src).await?;
 
-            let (file_dst, copied) = response_src
-                .bytes_stream()
-                .map_err(Error::from)
-                .try_fold((file_dst, 0), |(mut file_dst, copied), chunk| async move {
+        let decryption_failed = |reason| Error::Decryption {
+            object: object_src.name.to_owned(),
+            reason,
+        };
+        let decompression_failed = || Decompression {
+            object: object_src.name.to_owned(),
+        };
+        let (file_dst, copied, decryptor, mut gunzip) = response_src
+            .bytes_stream()
+            .map_err(Error::from)
+            .try_fold(
+                (file_dst, 0, decryptor, gunzip),
+                |(file_dst, copied, mut decryptor, mut gunzip), chunk| async move {
                     let copied = copied + chunk.len();
+                    let chunk = match &mut decryptor {
+                        Some(decryptor) => {
+                            Bytes::from(decryptor.push(&chunk).map_err(decryption_failed)?)
+                        }
+                        None => chunk,
+                    };
+                    let chunk = match &mut gunzip {
+                        Some(gunzip) => {
+                            Bytes::from(gunzip.push(&chunk).context(decompression_failed())?)
+                        }
+                        None => chunk,
+                    };
                     file_dst
                         .write_all(&chunk)
                         .await
                         .context(Io { path: path_dst })?;
-                    Ok((file_dst, copied))
-                })
-                .await?;
+                    Ok((file_dst, copied, decryptor, gunzip))
+                },
+            )
+            .await?;
+        if let Some(decryptor) = decryptor {
+            let last = decryptor.finish().map_err(decryption_failed)?;
+            let last = match &mut gunzip {
+                Some(gunzip) => gunzip.push(&last).context(decompression_failed())?,
+                None => last,
+            };
+            file_dst
+                .write_all(&last)
+                .await
+                .context(Io { path: path_dst })?;
+        }
+        if let Some(gunzip) = gunzip {
+            let last = gunzip.finish().context(decompression_failed())?;
+            file_dst
+                .write_all(&last)
+                .await
+                .context(Io { path: path_dst })?;
+        }
 
-            file_dst.sync_all().await.context(Io { path: path_dst })?;
-            count += 1;
+        Ok(copied)
+    }
 
-            log::trace!("Copied {} bytes", copied);
+    /// Decryptor for an object's content, `None` if it's stored unencrypted
+    fn decryptor(&self, object: &Object) -> Result<Option<Decryptor>> {
+        let failed = |reason| Error::Decryption {
+            object: object.name.to_owned(),
+            reason,
+        };
+        match (encryption_key_id(object), &self.encryption) {
+            (None, _) => Ok(None),
+            (Some(id), Some(key)) if id == key.id() => {
+                Ok(Some(Decryptor::new(key, content_id(object))))
+            }
+            (Some(_), Some(_)) => Err(failed("encrypted with another key")),
+            (Some(_), None) => Err(failed("encrypted, no key given")),
         }
-        Ok(count)
     }
 
-    async fn should_download(
-        force_overwrite: bool,
-        object: &Object,
-        path_dst: impl AsRef<Path>,
-    ) -> Result<bool> {
-        if force_overwrite {
+    /// Decompressor for an object's content, `None` if it's downloaded as stored
+    fn gunzip(&self, object: &Object) -> Option<Gunzip> {
+        if self.decompress && is_gzipped(object) {
+            Some(Gunzip::new())
+        } else {
+            None
+        }
+    }
+
+    async fn should_download(&self, object: &Object, path_dst: impl AsRef<Path>) -> Result<bool> {
+        if self.force_overwrite || self.comparison == Comparison::Always {
             return Ok(true);
         }
 
@@ -306,12 +953,19 @@ impl GcsSource {
             })?
             .len();
 
-        if dst_len != object.size {
-            log::trace!("Size mismatch, src: {}, dst: {}", object.size, dst_len);
+        // gzipped content is compared as stored unless it's decompressed
+        let (src_len, src_crc32c) = if is_gzipped(object) && !self.decompress {
+            (object.size, object.crc32c_decode())
+        } else {
+            plaintext_size_crc32c(object)
+        };
+        if dst_len != src_len {
+            log::trace!("Size mismatch, src: {}, dst: {}", src_len, dst_len);
             Ok(true)
-        } else if file_crc32c(path_dst.as_ref()).await.context(Io {
-            path: path_dst.as_ref(),
-        })? != object.crc32c_decode()
+        } else if self.comparison == Comparison::Checksum
+            && file_crc32c(path_dst.as_ref()).await.context(Io {
+                path: path_dst.as_ref(),
+            })? != src_crc32c
         {
             log::trace!("Crc32c mismatch");
             Ok(true)
@@ -320,3 +974,66 @@ impl GcsSource {
         }
     }
 }
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "symlinks are only supported on unix",
+    ))
+}
+
+/// Maps an object name under `path_src` to the corresponding name under `path_dst`,
+/// `None` if the object is not `path_src` itself or inside it
+fn copy_destination(path_src: &str, path_dst: &str, name: &str) -> Option<String> {
+    if name == path_src {
+        return Some(path_dst.to_owned());
+    }
+    let relative = if path_src.is_empty() || path_src.ends_with('/') {
+        name.strip_prefix(path_src)
+    } else {
+        name.strip_prefix(path_src)
+            .and_then(|rest| rest.strip_prefix('/'))
+    }?;
+    Some(join_object_name(path_dst, relative))
+}
+
+fn check_symlink_target(root: &Path, path_dst: &Path, target: &Path) -> Result<()> {
+    if symlink_escapes(root, path_dst, target) {
+        return Err(Error::UnsafeSymlink {
+            path: path_dst.to_owned(),
+            target: target.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Fails if a directory between `root` and `path_dst` is a symlink,
+/// writes through it could end up outside of `root`
+pub(crate) fn check_no_symlink_parents(root: &Path, path_dst: &Path) -> Result<()> {
+    let relative = match path_dst.parent().map(|parent| parent.strip_prefix(root)) {
+        Some(Ok(relative)) => relative,
+        _ => return Ok(()),
+    };
+    let mut dir = root.to_owned();
+    for component in relative.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(Error::SymlinkInPath {
+                    path: path_dst.to_owned(),
+                    symlink: dir,
+                })
+            }
+            // the rest doesn't exist yet
+            Err(_) => return Ok(()),
+            Ok(_) => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,510 @@
+//! Sync jobs declared in a TOML file
+
+use crate::api::Client;
+use crate::auth::Credentials;
+use crate::error::*;
+use crate::gcs::GcsSource;
+use crate::local::{Comparison, LocalSource, SymlinkPolicy};
+use crate::rules::{Filter, MetadataRule, StorageClass};
+use crate::Result;
+use futures::future;
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A local path or a `gs://bucket/prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Local(PathBuf),
+    Gcs { bucket: String, prefix: String },
+}
+
+impl std::str::FromStr for Location {
+    type Err = Error;
+
+    fn from_str(location: &str) -> Result<Self> {
+        let path = match location.strip_prefix("gs://") {
+            Some(path) => path,
+            None => return Ok(Self::Local(PathBuf::from(location))),
+        };
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(Error::InvalidLocation {
+                location: location.to_owned(),
+            });
+        }
+        Ok(Self::Gcs {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local(path) => write!(f, "{}", path.display()),
+            Self::Gcs { bucket, prefix } => write!(f, "gs://{}/{}", bucket, prefix),
+        }
+    }
+}
+
+/// Sync jobs read from a TOML file
+///
+/// ```toml
+/// # jobs run one after another unless set
+/// parallel = true
+///
+/// [[job]]
+/// name = "site"
+/// source = "./site"
+/// destination = "gs://my-bucket/site"
+/// exclude = ["*.tmp", ".git"]
+/// compare = "size"
+/// schedule = "10m"
+///
+/// [[job.metadata]]
+/// glob = "*.html"
+/// cache_control = "no-cache"
+///
+/// [[job]]
+/// source = "gs://my-bucket/reports"
+/// destination = "/var/reports"
+/// include = ["*.csv"]
+/// ```
+///
+/// Other job fields are `concurrency`, `force`, `dry_run`, `symlinks`, `preserve_times`,
+/// `preserve_permissions`, `storage_class`, `credentials`, a path to a service account
+/// key used instead of the one in `SERVICE_ACCOUNT`, and `billing_project`, the project
+/// requests to requester-pays buckets are billed to, metadata rules also take
+/// `content_type`, `content_disposition`, `content_language`, `storage_class` and
+/// a `metadata` table
+#[derive(Debug)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+    parallel: bool,
+}
+
+/// A configured sync of one source to one destination
+#[derive(Debug)]
+pub struct Job {
+    name: String,
+    schedule: Option<Duration>,
+    run: Run,
+}
+
+#[derive(Debug)]
+enum Run {
+    Upload {
+        local: LocalSource,
+        path: PathBuf,
+        bucket: String,
+        prefix: String,
+    },
+    Download {
+        gcs: GcsSource,
+        bucket: String,
+        prefix: String,
+        path: PathBuf,
+    },
+    Copy {
+        gcs: GcsSource,
+        bucket: String,
+        prefix: String,
+        bucket_dst: String,
+        prefix_dst: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobsSpec {
+    #[serde(default)]
+    parallel: bool,
+    #[serde(default)]
+    job: Vec<JobSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobSpec {
+    name: Option<String>,
+    source: String,
+    destination: String,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    compare: Comparison,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    symlinks: SymlinkPolicy,
+    #[serde(default)]
+    preserve_times: bool,
+    #[serde(default)]
+    preserve_permissions: bool,
+    storage_class: Option<StorageClass>,
+    #[serde(default)]
+    metadata: Vec<MetadataSpec>,
+    schedule: Option<String>,
+    credentials: Option<PathBuf>,
+    billing_project: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetadataSpec {
+    glob: String,
+    content_type: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    content_language: Option<String>,
+    storage_class: Option<StorageClass>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+impl Jobs {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path).context(Io { path })?;
+        Self::from_toml(&toml)
+    }
+
+    /// Parses and validates jobs, errors name the offending field, e.g. `job[1].exclude[0]`
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let spec: JobsSpec = toml::from_str(toml).context(JobFile)?;
+        let mut names = HashSet::new();
+        // jobs share connections, and tokens unless they have credentials of their own
+        let client = Client::default();
+        let jobs = spec
+            .job
+            .into_iter()
+            .enumerate()
+            .map(|(index, job)| {
+                let job = Job::from_spec(&format!("job[{}]", index), job, &client)?;
+                if !names.insert(job.name.clone()) {
+                    return Err(invalid(
+                        format!("job[{}].name", index),
+                        format!("another job is named {:?}", job.name),
+                    ));
+                }
+                Ok(job)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            jobs,
+            parallel: spec.parallel,
+        })
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Runs every job once, returns each job's name and operation count or error
+    pub async fn run(&self) -> Vec<(&str, Result<usize>)> {
+        if self.parallel {
+            future::join_all(
+                self.jobs
+                    .iter()
+                    .map(|job| async move { (job.name(), job.run().await) }),
+            )
+            .await
+        } else {
+            let mut results = vec![];
+            for job in &self.jobs {
+                results.push((job.name(), job.run().await));
+            }
+            results
+        }
+    }
+
+    /// Runs every job, then runs scheduled jobs again and again once their interval
+    /// has passed since the previous run started. Failures are logged and the job is
+    /// retried on schedule, returns after the first round if no job is scheduled
+    pub async fn run_scheduled(&self) {
+        if self.parallel {
+            future::join_all(self.jobs.iter().map(|job| async move {
+                loop {
+                    let started = Instant::now();
+                    job.run_logged().await;
+                    match job.schedule {
+                        Some(schedule) => tokio::time::sleep_until(started + schedule).await,
+                        None => return,
+                    }
+                }
+            }))
+            .await;
+            return;
+        }
+
+        // jobs are due in order, and never overlap
+        let now = Instant::now();
+        let mut due: Vec<_> = self.jobs.iter().map(|job| (now, job)).collect();
+        while !due.is_empty() {
+            let (index, &(at, job)) = due
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (at, _))| *at)
+                .unwrap();
+            tokio::time::sleep_until(at).await;
+            let started = Instant::now();
+            job.run_logged().await;
+            match job.schedule {
+                Some(schedule) => due[index].0 = started + schedule,
+                None => {
+                    due.remove(index);
+                }
+            }
+        }
+    }
+}
+
+impl Job {
+    fn from_spec(field: &str, spec: JobSpec, client: &Client) -> Result<Self> {
+        let JobSpec {
+            name,
+            source,
+            destination,
+            concurrency,
+            force,
+            compare,
+            dry_run,
+            include,
+            exclude,
+            symlinks,
+            preserve_times,
+            preserve_permissions,
+            storage_class,
+            metadata,
+            schedule,
+            credentials,
+            billing_project,
+        } = spec;
+        let field = |name: &str| format!("{}.{}", field, name);
+        let location = |name: &str, location: &str| {
+            location
+                .parse::<Location>()
+                .map_err(|e| invalid(field(name), e))
+        };
+        let source = location("source", &source)?;
+        let destination = location("destination", &destination)?;
+        if concurrency == 0 {
+            return Err(invalid(field("concurrency"), "must be positive"));
+        }
+
+        let mut filter = Filter::default();
+        for (index, glob) in include.iter().enumerate() {
+            filter = filter
+                .with_include(glob)
+                .map_err(|e| invalid(format!("{}[{}]", field("include"), index), e))?;
+        }
+        for (index, glob) in exclude.iter().enumerate() {
+            filter = filter
+                .with_exclude(glob)
+                .map_err(|e| invalid(format!("{}[{}]", field("exclude"), index), e))?;
+        }
+        let rules = metadata
+            .into_iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                metadata_rule(rule)
+                    .map_err(|e| invalid(format!("{}[{}].glob", field("metadata"), index), e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let schedule = schedule
+            .as_deref()
+            .map(|schedule| parse_interval(schedule).ok_or(schedule))
+            .transpose()
+            .map_err(|schedule| {
+                invalid(
+                    field("schedule"),
+                    format!("{:?} is not an interval like 30s, 10m, 1h or 1d", schedule),
+                )
+            })?;
+        let mut client = match credentials {
+            Some(path) => Credentials::from_service_account_file(path)
+                .map(|credentials| client.clone().with_credentials(credentials))
+                .map_err(|e| invalid(field("credentials"), e))?,
+            None => client.clone(),
+        };
+        if let Some(project) = billing_project {
+            client = client.with_billing_project(project);
+        }
+
+        let name = name.unwrap_or_else(|| format!("{} -> {}", source, destination));
+        let local = || {
+            let mut local = LocalSource::new(force, concurrency)
+                .with_client(client.clone())
+                .with_filter(filter.clone())
+                .with_dry_run(dry_run)
+                .with_comparison(compare)
+                .with_symlinks(symlinks)
+                .with_preserve_times(preserve_times)
+                .with_preserve_permissions(preserve_permissions);
+            if let Some(storage_class) = storage_class {
+                local = local.with_storage_class(storage_class);
+            }
+            rules
+                .iter()
+                .fold(local, |local, rule| local.with_metadata_rule(rule.clone()))
+        };
+        let gcs = || {
+            let mut gcs = GcsSource::new(force, concurrency)
+                .with_client(client.clone())
+                .with_filter(filter.clone())
+                .with_dry_run(dry_run)
+                .with_comparison(compare)
+                .with_symlinks(symlinks)
+                .with_preserve_times(preserve_times)
+                .with_preserve_permissions(preserve_permissions);
+            if let Some(storage_class) = storage_class {
+                gcs = gcs.with_storage_class(storage_class);
+            }
+            rules
+                .iter()
+                .fold(gcs, |gcs, rule| gcs.with_metadata_rule(rule.clone()))
+        };
+        let run = match (source, destination) {
+            (Location::Local(path), Location::Gcs { bucket, prefix }) => Run::Upload {
+                local: local(),
+                path,
+                bucket,
+                prefix,
+            },
+            (Location::Gcs { bucket, prefix }, Location::Local(path)) => Run::Download {
+                gcs: gcs(),
+                bucket,
+                prefix,
+                path,
+            },
+            (
+                Location::Gcs { bucket, prefix },
+                Location::Gcs {
+                    bucket: bucket_dst,
+                    prefix: prefix_dst,
+                },
+            ) => Run::Copy {
+                gcs: gcs(),
+                bucket,
+                prefix,
+                bucket_dst,
+                prefix_dst,
+            },
+            (Location::Local(_), Location::Local(_)) => {
+                return Err(invalid(
+                    field("destination"),
+                    "source or destination must be gs://bucket/prefix",
+                ))
+            }
+        };
+        Ok(Self {
+            name,
+            schedule,
+            run,
+        })
+    }
+
+    /// `name` of the job, `source -> destination` if it's not set
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn run_logged(&self) {
+        match self.run().await {
+            Ok(count) => log::info!("Job {}: {} operations", self.name, count),
+            Err(e) => log::error!("Job {} failed: {}", self.name, e),
+        }
+    }
+
+    /// Interval the job is run at by [Jobs::run_scheduled]
+    pub fn schedule(&self) -> Option<Duration> {
+        self.schedule
+    }
+
+    /// Syncs the source to the destination once, returns operations count
+    pub async fn run(&self) -> Result<usize> {
+        log::trace!("Running job {}", self.name);
+        match &self.run {
+            Run::Upload {
+                local,
+                path,
+                bucket,
+                prefix,
+            } => local.to_gcs(path, bucket, prefix).await,
+            Run::Download {
+                gcs,
+                bucket,
+                prefix,
+                path,
+            } => gcs.to_local(bucket, prefix, path).await,
+            Run::Copy {
+                gcs,
+                bucket,
+                prefix,
+                bucket_dst,
+                prefix_dst,
+            } => gcs.to_gcs(bucket, prefix, bucket_dst, prefix_dst).await,
+        }
+    }
+}
+
+fn metadata_rule(spec: MetadataSpec) -> Result<MetadataRule> {
+    let mut rule = MetadataRule::new(&spec.glob)?;
+    if let Some(content_type) = spec.content_type {
+        rule = rule.with_content_type(content_type);
+    }
+    if let Some(cache_control) = spec.cache_control {
+        rule = rule.with_cache_control(cache_control);
+    }
+    if let Some(content_disposition) = spec.content_disposition {
+        rule = rule.with_content_disposition(content_disposition);
+    }
+    if let Some(content_language) = spec.content_language {
+        rule = rule.with_content_language(content_language);
+    }
+    if let Some(storage_class) = spec.storage_class {
+        rule = rule.with_storage_class(storage_class);
+    }
+    Ok(spec
+        .metadata
+        .into_iter()
+        .fold(rule, |rule, (key, value)| rule.with_metadata(key, value)))
+}
+
+/// Parses an interval like `90s`, `10m`, `1h` or `1d`
+fn parse_interval(interval: &str) -> Option<Duration> {
+    let unit = match interval.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = interval[..interval.len() - 1].parse().ok()?;
+    match count.checked_mul(unit)? {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+fn invalid(field: String, reason: impl ToString) -> Error {
+    Error::InvalidJob {
+        field,
+        reason: reason.to_string(),
+    }
+}
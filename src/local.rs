@@ -1,30 +1,226 @@
+use crate::api::*;
+use crate::auth::Credentials;
+use crate::compression::*;
+use crate::crypto::*;
 use crate::error::*;
+use crate::names::*;
+use crate::posix::*;
+use crate::rules::*;
+use crate::sync::{report, Progress, SyncEvent};
 use crate::util::*;
 use crate::Result;
-use cloud_storage::{object::Object, Client};
-use futures::future::{BoxFuture, FutureExt};
+use bytes::Bytes;
+use cloud_storage::object::Object;
+use futures::future::{BoxFuture, Either, FutureExt};
 use futures::stream::TryStreamExt;
 use snafu::{futures::TryStreamExt as SnafuTryStreamExt, ResultExt};
 use std::path::{Path, PathBuf};
 use tokio::fs::{self, File};
 
+/// Custom metadata key holding the target of a symlink stored as an object
+pub(crate) const SYMLINK_TARGET_KEY: &str = "cloud-storage-sync-symlink-target";
+
+/// How symbolic links are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Sync the file or directory the link points to,
+    /// links leading back to a directory being synced are skipped
+    #[default]
+    Follow,
+    /// Ignore links
+    Skip,
+    /// Store the link as a small object holding its target in
+    /// `cloud-storage-sync-symlink-target` metadata and recreate the link on download
+    Preserve,
+}
+
+/// How existing files and objects are compared to decide whether content is transferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    /// Same size and crc32c checksum
+    #[default]
+    Checksum,
+    /// Same size, files aren't read to compute checksums
+    Size,
+    /// Content is always transferred
+    Always,
+}
+
+/// Objects already stored under another prefix of the same bucket, e.g. a previous snapshot,
+/// files matching them are copied server-side instead of being uploaded
+#[derive(Debug, Clone)]
+pub(crate) struct Reference {
+    pub(crate) bucket: String,
+    pub(crate) src_prefix: String,
+    pub(crate) dst_prefix: String,
+}
+
+impl Reference {
+    /// Name of the object a destination object name may be copied from
+    fn source_name(&self, name: &str) -> Option<String> {
+        name.strip_prefix(&self.dst_prefix)
+            .map(|rest| format!("{}{}", self.src_prefix, rest))
+    }
+}
+
 #[derive(Debug)]
 pub struct LocalSource {
     pub(crate) force_overwrite: bool,
     pub(crate) concurrency: usize,
     pub(crate) client: Client,
+    pub(crate) preserve: Preserve,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) names: NameEncoding,
+    pub(crate) encryption: Option<EncryptionKey>,
+    pub(crate) server_encryption: Option<ServerEncryption>,
+    pub(crate) gzip: Option<Gzip>,
+    pub(crate) metadata_rules: Vec<MetadataRule>,
+    pub(crate) object_defaults: ObjectProperties,
+    pub(crate) filter: Filter,
+    pub(crate) dry_run: bool,
+    pub(crate) comparison: Comparison,
+    pub(crate) progress: Option<Progress>,
 }
 
 impl LocalSource {
     pub fn new(force_overwrite: bool, concurrency: usize) -> Self {
-        let client = Client::default();
         Self {
             force_overwrite,
             concurrency,
-            client,
+            client: Client::default(),
+            preserve: Preserve::default(),
+            symlinks: SymlinkPolicy::default(),
+            names: NameEncoding::default(),
+            encryption: None,
+            server_encryption: None,
+            gzip: None,
+            metadata_rules: vec![],
+            object_defaults: ObjectProperties::default(),
+            filter: Filter::default(),
+            dry_run: false,
+            comparison: Comparison::default(),
+            progress: None,
         }
     }
 
+    /// Encrypts file content before it's uploaded,
+    /// objects stored without encryption or with another key are uploaded again
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Has Cloud Storage encrypt uploaded objects with a customer-supplied or managed key,
+    /// objects encrypted otherwise are uploaded again
+    pub fn with_server_encryption(mut self, encryption: ServerEncryption) -> Self {
+        self.server_encryption = Some(encryption);
+        self
+    }
+
+    /// Uploads files matching the rules gzipped with `Content-Encoding: gzip`,
+    /// files encrypted client-side are never compressed
+    pub fn with_gzip(mut self, gzip: Gzip) -> Self {
+        self.gzip = Some(gzip);
+        self
+    }
+
+    /// Sets content type, `Cache-Control` and other properties of objects uploaded
+    /// from files matching the rule, properties of later rules override earlier ones.
+    /// Objects with matching content but outdated properties are updated without re-upload
+    pub fn with_metadata_rule(mut self, rule: MetadataRule) -> Self {
+        self.metadata_rules.push(rule);
+        self
+    }
+
+    /// Writes objects with a storage class unless a metadata rule sets another one,
+    /// objects with matching content stored with another class are rewritten server-side
+    pub fn with_storage_class(mut self, storage_class: StorageClass) -> Self {
+        self.object_defaults.storage_class = Some(storage_class);
+        self
+    }
+
+    /// Places or releases a temporary hold on uploaded objects unless a metadata rule overrides it
+    pub fn with_temporary_hold(mut self, hold: bool) -> Self {
+        self.object_defaults.temporary_hold = Some(hold);
+        self
+    }
+
+    /// Places or releases an event-based hold on uploaded objects
+    /// unless a metadata rule overrides it
+    pub fn with_event_based_hold(mut self, hold: bool) -> Self {
+        self.object_defaults.event_based_hold = Some(hold);
+        self
+    }
+
+    /// Sets how local file names are converted to object names,
+    /// [NameEncoding::Utf8] by default
+    pub fn with_name_encoding(mut self, names: NameEncoding) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Sets how symbolic links found inside synced directories are handled,
+    /// [SymlinkPolicy::Follow] by default
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Stores file modification and access times in `goog-reserved-file-mtime`
+    /// and `goog-reserved-file-atime` object metadata, the same way `gsutil -P` does
+    pub fn with_preserve_times(mut self, preserve_times: bool) -> Self {
+        self.preserve.times = preserve_times;
+        self
+    }
+
+    /// Stores file permissions, including executable bits, in `goog-reserved-posix-mode` object metadata
+    pub fn with_preserve_permissions(mut self, preserve_permissions: bool) -> Self {
+        self.preserve.mode = preserve_permissions;
+        self
+    }
+
+    /// Syncs only files and directories passing the filter,
+    /// names are matched relative to the synced directory
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Authorizes requests with the credentials instead of the service account key
+    /// at the path in `SERVICE_ACCOUNT`
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.client = self.client.with_credentials(credentials);
+        self
+    }
+
+    /// Bills requests to the project, required to sync with requester-pays buckets
+    pub fn with_billing_project(mut self, project: impl Into<String>) -> Self {
+        self.client = self.client.with_billing_project(project);
+        self
+    }
+
+    /// Counts and logs uploads and updates a sync would make without making them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets how files are compared to existing objects, [Comparison::Checksum] by default
+    pub fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparison = comparison;
+        self
+    }
+
+    /// Sends requests through `client`, sharing its connections and tokens
+    /// with other sources
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Client requests are sent through
     pub fn client(&self) -> &Client {
         &self.client
     }
@@ -37,36 +233,66 @@ impl LocalSource {
         path_src: impl AsRef<Path>,
         bucket_dst: &str,
         path_dst: &str,
+    ) -> Result<usize, Error> {
+        self.to_gcs_referencing(path_src, bucket_dst, path_dst, None)
+            .await
+    }
+
+    /// [LocalSource::to_gcs] copying content found in `reference` instead of uploading it
+    pub(crate) async fn to_gcs_referencing(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket_dst: &str,
+        path_dst: &str,
+        reference: Option<Reference>,
     ) -> Result<usize, Error> {
         let path_buf = PathBuf::from(path_src.as_ref());
+        // metadata rules match names relative to it
+        let root = join_object_name(path_dst, "");
         if path_buf.is_dir() {
+            let canonical = fs::canonicalize(&path_buf)
+                .await
+                .context(TokioIo { path: &path_buf })?;
             self.sync_local_dir_to_gcs(
-                path_src.to_str_wrap()?.to_owned(),
+                path_buf,
                 bucket_dst.to_owned(),
                 path_dst.to_owned(),
+                root,
+                vec![canonical],
+                reference,
             )
             .await
         } else {
             let filename = path_buf.file_name().ok_or(Error::Other {
                 message: "path_src is not a file, should never happen, please report an issue",
             })?;
-            let path_dst = PathBuf::from(path_dst).join(filename);
-            let gcs_path_dst = path_dst.to_str_wrap()?;
-            self.sync_local_file_to_gcs(path_src, bucket_dst, gcs_path_dst)
-                .await
+            let gcs_path_dst = join_object_name(path_dst, &self.names.encode(filename)?);
+            self.sync_local_file_to_gcs(
+                path_src,
+                bucket_dst,
+                &gcs_path_dst,
+                &root,
+                reference.as_ref(),
+            )
+            .await
         }
     }
 
     /// Syncs local directory to gcs bucket
     /// the resulting filenames will be [path_dst]/[filename]
     /// where [filename] is path relative to the path_src
-    fn sync_local_dir_to_gcs(
+    ///
+    /// ancestors are canonical paths of directories being synced,
+    /// used to detect symlink cycles
+    pub(crate) fn sync_local_dir_to_gcs(
         &self,
-        // path_src: impl AsRef<Path>,
-        path_src: String,
+        path_src: PathBuf,
         bucket: String,
         path_dst: String,
-    ) -> BoxFuture<Result<usize>> {
+        root: String,
+        ancestors: Vec<PathBuf>,
+        reference: Option<Reference>,
+    ) -> BoxFuture<'_, Result<usize>> {
         async move {
             // get dir entries
             let entries = fs::read_dir(&path_src).await.context(TokioIo {
@@ -75,49 +301,153 @@ impl LocalSource {
             // convert to stream
             let entries = tokio_stream::wrappers::ReadDirStream::new(entries);
 
-            let (entry_count, op_count) = entries
-                .context(Io { path: path_src })
-                .map_ok(|entry| (entry, bucket.clone(), path_dst.clone()))
-                .and_then(|(entry, bucket, path_dst)| async move {
-                    let entry_path = entry.path();
-                    let path_dst = PathBuf::from(&path_dst).join(entry.file_name());
-                    let path_dst = path_dst.to_str_wrap()?.to_owned();
-                    if entry_path.is_dir() {
-                        self.sync_local_dir_to_gcs(
-                            entry_path.to_str_wrap()?.to_owned(),
-                            bucket.clone(),
-                            path_dst.clone(),
-                        )
-                        .await
-                    } else {
-                        self.sync_local_file_to_gcs(&entry_path, &bucket, &path_dst)
-                            .await
-                    }
+            let (entry_count, mut op_count, subdirs) = entries
+                .context(Io { path: &path_src })
+                .map_ok(|entry| {
+                    (
+                        entry,
+                        bucket.clone(),
+                        path_dst.clone(),
+                        root.clone(),
+                        ancestors.clone(),
+                        reference.clone(),
+                    )
                 })
+                .map_ok(
+                    |(entry, bucket, path_dst, root, mut ancestors, reference)| async move {
+                        let entry_path = entry.path();
+                        let path_dst =
+                            join_object_name(&path_dst, &self.names.encode(&entry.file_name())?);
+                        let relative = path_dst.strip_prefix(&root).unwrap_or(&path_dst);
+                        let file_type = entry
+                            .file_type()
+                            .await
+                            .context(TokioIo { path: &entry_path })?;
+                        if file_type.is_symlink() {
+                            match self.symlinks {
+                                SymlinkPolicy::Skip => {
+                                    log::trace!("Skip symlink {:?}", entry_path);
+                                    return Ok((0, None));
+                                }
+                                SymlinkPolicy::Preserve if self.filter.excludes(relative) => {
+                                    log::trace!("Skip filtered {:?}", entry_path);
+                                    return Ok((0, None));
+                                }
+                                SymlinkPolicy::Preserve => {
+                                    let count = self
+                                        .sync_local_symlink_to_gcs(&entry_path, &bucket, &path_dst)
+                                        .await?;
+                                    return Ok((count, None));
+                                }
+                                SymlinkPolicy::Follow => {}
+                            }
+                        }
+                        if entry_path.is_dir() {
+                            if self.filter.excludes(&format!("{}/", relative)) {
+                                log::trace!("Skip filtered {:?}", entry_path);
+                                return Ok((0, None));
+                            }
+                            let canonical = fs::canonicalize(&entry_path)
+                                .await
+                                .context(TokioIo { path: &entry_path })?;
+                            if ancestors.contains(&canonical) {
+                                log::warn!(
+                                    "Skip {:?}, symlink cycle back to {:?}",
+                                    entry_path,
+                                    canonical
+                                );
+                                return Ok((0, None));
+                            }
+                            ancestors.push(canonical);
+                            Ok((0, Some((entry_path, path_dst, ancestors))))
+                        } else {
+                            let count = self
+                                .sync_local_file_to_gcs(
+                                    &entry_path,
+                                    &bucket,
+                                    &path_dst,
+                                    &root,
+                                    reference.as_ref(),
+                                )
+                                .await?;
+                            Ok((count, None))
+                        }
+                    },
+                )
+                // subdirectories are synced afterwards, one at a time,
+                // so no more than `concurrency` uploads run at once
+                .try_buffer_unordered(self.concurrency)
                 .try_fold(
-                    (0usize, 0usize),
-                    |(entry_count, op_count), entry_op_count| async move {
-                        Ok((entry_count + 1, op_count + entry_op_count))
+                    (0usize, 0usize, vec![]),
+                    |(entry_count, op_count, mut subdirs), (entry_op_count, subdir)| async move {
+                        subdirs.extend(subdir);
+                        Ok((entry_count + 1, op_count + entry_op_count, subdirs))
                     },
                 )
                 .await?;
+            for (subdir, subdir_dst, ancestors) in subdirs {
+                op_count += self
+                    .sync_local_dir_to_gcs(
+                        subdir,
+                        bucket.clone(),
+                        subdir_dst,
+                        root.clone(),
+                        ancestors,
+                        reference.clone(),
+                    )
+                    .await?;
+            }
 
             if entry_count == 0 {
                 // empty directory, create an object/
                 let dir_object = format!("{}/", path_dst);
-                match Object::read(&bucket, &dir_object).await {
+                let existing = self
+                    .client
+                    .get(&bucket, &dir_object, None, self.server_encryption.as_ref())
+                    .await;
+                match existing {
                     Ok(_) => Ok(0),
                     Err(cloud_storage::Error::Google(response))
                         if response.errors_has_reason(&cloud_storage::Reason::NotFound) =>
                     {
+                        if self.dry_run {
+                            log::info!("Would create gs://{}/{}", bucket, dir_object);
+                            return Ok(1);
+                        }
                         log::trace!("Creating gs://{}{}", bucket, dir_object);
-                        Object::create(&bucket, vec![], &dir_object, "")
-                            .await
-                            .context(CloudStorage {
-                                object: dir_object,
-                                op: OpSource::CreateObject,
-                            })?;
-                        Ok(1)
+                        let new_object = NewObject {
+                            name: dir_object.clone(),
+                            content_type: mime::APPLICATION_OCTET_STREAM.essence_str().to_owned(),
+                            ..Default::default()
+                        };
+                        let created = self
+                            .client
+                            .upload(
+                                &bucket,
+                                &new_object,
+                                futures::stream::empty(),
+                                0,
+                                Some(0),
+                                self.server_encryption.as_ref(),
+                            )
+                            .await;
+                        match created {
+                            // created concurrently by someone else
+                            Err(e) if is_precondition_failure(&e) => Ok(0),
+                            created => {
+                                created.context(CloudStorage {
+                                    object: dir_object.clone(),
+                                    op: OpSource::CreateObject,
+                                })?;
+                                report(self.progress.as_ref(), || SyncEvent::Uploaded {
+                                    path: path_src.clone(),
+                                    bucket: bucket.clone(),
+                                    object: dir_object,
+                                    bytes: 0,
+                                });
+                                Ok(1)
+                            }
+                        }
                     }
                     Err(e) => Err(e).context(CloudStorage {
                         object: dir_object,
@@ -133,46 +463,328 @@ impl LocalSource {
     }
 
     /// Syncs local file and remote object
-    async fn sync_local_file_to_gcs(
+    ///
+    /// `root` is the object name prefix the synced directory is uploaded to
+    pub(crate) async fn sync_local_file_to_gcs(
         &self,
         path_src: impl AsRef<Path>,
         bucket: &str,
         filename: &str,
+        root: &str,
+        reference: Option<&Reference>,
     ) -> Result<usize> {
-        if !self
-            .should_upload_local(path_src.as_ref(), bucket, filename)
-            .await?
-        {
-            log::trace!("Skip {:?}", path_src.as_ref());
-            Ok(0)
-        } else {
-            log::trace!(
-                "Copy {:?} to gs://{}/{}",
+        let relative = filename.strip_prefix(root).unwrap_or(filename);
+        if self.filter.excludes(relative) {
+            log::trace!("Skip filtered {:?}", path_src.as_ref());
+            return Ok(0);
+        }
+        let properties = self.object_properties(relative);
+        // read attributes before the crc32c comparison touches atime
+        let attrs = PosixAttrs::from_metadata(
+            &path_src.as_ref().metadata().context(Io {
+                path: path_src.as_ref(),
+            })?,
+            self.preserve,
+        );
+        match self
+            .should_upload_local(
                 path_src.as_ref(),
                 bucket,
                 filename,
-            );
-            let file_src = File::open(path_src.as_ref()).await.context(Io {
-                path: path_src.as_ref(),
-            })?;
-            let metadata = file_src.metadata().await.context(Io {
-                path: path_src.as_ref(),
-            })?;
-            let length = metadata.len();
-            // let stream = ByteStream(Pin::new(Box::new(file_src)));
-            let stream = tokio_util::io::ReaderStream::new(file_src);
-            // let reader = BufReader::new(file_src);
-            let mime_type =
-                mime_guess::from_path(path_src).first_or(mime::APPLICATION_OCTET_STREAM);
-            let mime_type_str = mime_type.essence_str();
-            Object::create_streamed(bucket, stream, length, filename, mime_type_str)
-                .await
-                .context(CloudStorage {
+                &attrs,
+                &properties,
+                reference,
+            )
+            .await?
+        {
+            Upload::Skip => {
+                log::trace!("Skip {:?}", path_src.as_ref());
+                Ok(0)
+            }
+            _ if self.dry_run => {
+                log::info!(
+                    "Would sync {:?} to gs://{}/{}",
+                    path_src.as_ref(),
+                    bucket,
+                    filename
+                );
+                Ok(1)
+            }
+            Upload::Attrs(object) => {
+                log::trace!("Update attributes of gs://{}/{}", bucket, filename);
+                let mut properties = properties;
+                attrs.merge_into(&mut properties.metadata);
+                let patched = self
+                    .client
+                    .patch(bucket, filename, &properties, Some(object.metageneration))
+                    .await;
+                check_precondition(patched, bucket, filename, OpSource::UpdateObject)?;
+                report(self.progress.as_ref(), || SyncEvent::Updated {
+                    bucket: bucket.to_owned(),
+                    object: filename.to_owned(),
+                });
+                Ok(1)
+            }
+            Upload::Copy {
+                object,
+                if_generation_match,
+            } => {
+                log::trace!(
+                    "Copy gs://{}/{} to gs://{}/{}",
+                    object.bucket,
+                    object.name,
+                    bucket,
+                    filename
+                );
+                let copied = self
+                    .client
+                    .rewrite(
+                        &object,
+                        bucket,
+                        filename,
+                        properties.storage_class,
+                        if_generation_match,
+                        self.server_encryption.as_ref(),
+                    )
+                    .await;
+                let copied = check_precondition(copied, bucket, filename, OpSource::CopyObject)?;
+                if !attrs.stored_in(copied.metadata.as_ref()) || !properties.stored_in(&copied) {
+                    let mut properties = properties;
+                    attrs.merge_into(&mut properties.metadata);
+                    let patched = self
+                        .client
+                        .patch(bucket, filename, &properties, Some(copied.metageneration))
+                        .await;
+                    check_precondition(patched, bucket, filename, OpSource::UpdateObject)?;
+                }
+                report(self.progress.as_ref(), || SyncEvent::Copied {
+                    bucket: object.bucket.clone(),
+                    object: object.name.clone(),
+                    bucket_dst: bucket.to_owned(),
+                    object_dst: filename.to_owned(),
+                });
+                Ok(1)
+            }
+            Upload::Content {
+                if_generation_match,
+            } => {
+                let uploaded = self
+                    .upload_local_file(
+                        &path_src,
+                        bucket,
+                        filename,
+                        &attrs,
+                        &properties,
+                        if_generation_match,
+                    )
+                    .await?;
+                report(self.progress.as_ref(), || SyncEvent::Uploaded {
+                    path: path_src.as_ref().to_owned(),
+                    bucket: bucket.to_owned(),
                     object: filename.to_owned(),
-                    op: OpSource::CreateObject,
+                    bytes: uploaded.size,
+                });
+                Ok(1)
+            }
+        }
+    }
+
+    /// Uploads local file content, attributes and properties
+    ///
+    /// `if_generation_match` is the generation expected to be replaced,
+    /// `Some(0)` to upload only if the object doesn't exist
+    pub(crate) async fn upload_local_file(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket: &str,
+        filename: &str,
+        attrs: &PosixAttrs,
+        properties: &ObjectProperties,
+        if_generation_match: Option<i64>,
+    ) -> Result<Object> {
+        log::trace!(
+            "Copy {:?} to gs://{}/{}",
+            path_src.as_ref(),
+            bucket,
+            filename,
+        );
+        let file_src = File::open(path_src.as_ref()).await.context(Io {
+            path: path_src.as_ref(),
+        })?;
+        let metadata = file_src.metadata().await.context(Io {
+            path: path_src.as_ref(),
+        })?;
+        let length = metadata.len();
+        let mut metadata = properties.metadata.clone();
+        let mut content_encoding = None;
+        let (stream, length, mime_type) = match (&self.encryption, self.gzip(path_src.as_ref())) {
+            (Some(key), _) => {
+                let crc32c = file_crc32c(path_src.as_ref()).await.context(Io {
+                    path: path_src.as_ref(),
+                })?;
+                let content_id = new_content_id().context(Io {
+                    path: path_src.as_ref(),
                 })?;
-            Ok(1)
+                metadata.extend(encryption_metadata(key, &content_id, length, crc32c));
+                let stream = encrypt_file(key, &content_id, file_src, length).context(Io {
+                    path: path_src.as_ref(),
+                })?;
+                (
+                    Either::Left(stream),
+                    encrypted_len(length),
+                    mime::APPLICATION_OCTET_STREAM,
+                )
+            }
+            (None, Some(gzip)) => {
+                let crc32c = file_crc32c(path_src.as_ref()).await.context(Io {
+                    path: path_src.as_ref(),
+                })?;
+                metadata.extend(gzip_metadata(length, crc32c));
+                content_encoding = Some(GZIP_ENCODING.to_owned());
+                let (compressed, length) = gzip.compress_file(file_src).await.context(Io {
+                    path: path_src.as_ref(),
+                })?;
+                let stream = tokio_util::io::ReaderStream::new(compressed);
+                (
+                    Either::Right(Either::Left(stream)),
+                    length,
+                    guess_mime_type(path_src.as_ref()),
+                )
+            }
+            (None, None) => {
+                // let stream = ByteStream(Pin::new(Box::new(file_src)));
+                let stream = tokio_util::io::ReaderStream::new(file_src);
+                // let reader = BufReader::new(file_src);
+                (
+                    Either::Right(Either::Right(stream)),
+                    length,
+                    guess_mime_type(path_src.as_ref()),
+                )
+            }
+        };
+        let content_type = properties
+            .content_type
+            .clone()
+            .unwrap_or_else(|| mime_type.essence_str().to_owned());
+        if self.preserve.any() {
+            attrs.merge_into(&mut metadata);
+        }
+        let new_object = NewObject {
+            name: filename.to_owned(),
+            content_type,
+            content_encoding,
+            cache_control: properties.cache_control.clone(),
+            content_disposition: properties.content_disposition.clone(),
+            content_language: properties.content_language.clone(),
+            storage_class: properties.storage_class,
+            temporary_hold: properties.temporary_hold,
+            event_based_hold: properties.event_based_hold,
+            metadata: Some(metadata).filter(|metadata| !metadata.is_empty()),
+        };
+        let created = self
+            .client
+            .upload(
+                bucket,
+                &new_object,
+                stream,
+                length,
+                if_generation_match,
+                self.server_encryption.as_ref(),
+            )
+            .await;
+        check_precondition(created, bucket, filename, OpSource::CreateObject)
+    }
+
+    /// Syncs a symlink to an object containing its target
+    pub(crate) async fn sync_local_symlink_to_gcs(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket: &str,
+        filename: &str,
+    ) -> Result<usize> {
+        let path_src = path_src.as_ref();
+        let target = fs::read_link(path_src)
+            .await
+            .context(TokioIo { path: path_src })?;
+        let target = self.names.encode(target.as_os_str())?.into_owned();
+
+        let mut if_generation_match = None;
+        if !self.force_overwrite {
+            let existing = self
+                .client
+                .get(bucket, filename, None, self.server_encryption.as_ref())
+                .await;
+            match existing {
+                Ok(object) => {
+                    let stored = object
+                        .metadata
+                        .as_ref()
+                        .and_then(|metadata| metadata.get(SYMLINK_TARGET_KEY));
+                    if stored == Some(&target)
+                        && ServerEncryption::applied_to(self.server_encryption.as_ref(), &object)
+                    {
+                        log::trace!("Skip symlink {:?}", path_src);
+                        return Ok(0);
+                    }
+                    if_generation_match = Some(object.generation);
+                }
+                Err(e) if is_not_found(&e) => if_generation_match = Some(0),
+                Err(e) => {
+                    return Err(e).context(CloudStorage {
+                        object: filename.to_owned(),
+                        op: OpSource::ReadObject,
+                    })
+                }
+            }
         }
+
+        if self.dry_run {
+            log::info!(
+                "Would sync symlink {:?} to gs://{}/{}",
+                path_src,
+                bucket,
+                filename
+            );
+            return Ok(1);
+        }
+        log::trace!(
+            "Copy symlink {:?} -> {} to gs://{}/{}",
+            path_src,
+            target,
+            bucket,
+            filename
+        );
+        let content = Bytes::from(target.clone().into_bytes());
+        let length = content.len() as u64;
+        let new_object = NewObject {
+            name: filename.to_owned(),
+            content_type: mime::TEXT_PLAIN.essence_str().to_owned(),
+            metadata: Some(
+                vec![(SYMLINK_TARGET_KEY.to_owned(), target)]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let created = self
+            .client
+            .upload(
+                bucket,
+                &new_object,
+                futures::stream::once(async move { Ok(content) }),
+                length,
+                if_generation_match,
+                self.server_encryption.as_ref(),
+            )
+            .await;
+        check_precondition(created, bucket, filename, OpSource::CreateObject)?;
+        report(self.progress.as_ref(), || SyncEvent::Uploaded {
+            path: path_src.to_owned(),
+            bucket: bucket.to_owned(),
+            object: filename.to_owned(),
+            bytes: length,
+        });
+        Ok(1)
     }
 
     async fn should_upload_local(
@@ -180,9 +792,14 @@ impl LocalSource {
         path_src: impl AsRef<Path>,
         bucket: &str,
         filename: &str,
-    ) -> Result<bool> {
-        if self.force_overwrite {
-            return Ok(true);
+        attrs: &PosixAttrs,
+        properties: &ObjectProperties,
+        reference: Option<&Reference>,
+    ) -> Result<Upload> {
+        if self.force_overwrite || self.comparison == Comparison::Always {
+            return Ok(Upload::Content {
+                if_generation_match: None,
+            });
         }
 
         let src_len = path_src
@@ -192,34 +809,156 @@ impl LocalSource {
                 path: path_src.as_ref(),
             })?
             .len();
-        if let Ok(object) = self.client.object().read(bucket, filename).await {
-            if object.size != src_len {
-                log::trace!("Size mismatch, src: {}, dst: {}", src_len, object.size);
-                Ok(true)
-            } else if file_crc32c(path_src.as_ref()).await.context(Io {
-                path: path_src.as_ref(),
-            })? != object.crc32c_decode()
-            {
-                log::trace!("Crc32c mismatch");
-                Ok(true)
-            } else {
-                Ok(false)
+        // crc32c is reported for objects encrypted with a customer-supplied key
+        // only when the key is sent, md5 is never used
+        let existing = self
+            .client
+            .get(bucket, filename, None, self.server_encryption.as_ref())
+            .await;
+        match existing {
+            Ok(object) => {
+                // overwrite only the generation compared here
+                let if_generation_match = Some(object.generation);
+                let (dst_len, dst_crc32c) = plaintext_size_crc32c(&object);
+                if !encrypted_with(&object, self.encryption.as_ref()) {
+                    log::trace!("Encryption mismatch");
+                    Ok(Upload::Content {
+                        if_generation_match,
+                    })
+                } else if !ServerEncryption::applied_to(self.server_encryption.as_ref(), &object) {
+                    log::trace!("Server-side encryption mismatch");
+                    Ok(Upload::Content {
+                        if_generation_match,
+                    })
+                } else if is_gzipped(&object) != self.gzip(path_src.as_ref()).is_some() {
+                    log::trace!("Compression mismatch");
+                    Ok(Upload::Content {
+                        if_generation_match,
+                    })
+                } else if dst_len != src_len {
+                    log::trace!("Size mismatch, src: {}, dst: {}", src_len, dst_len);
+                    Ok(Upload::Content {
+                        if_generation_match,
+                    })
+                } else if self.comparison == Comparison::Checksum
+                    && file_crc32c(path_src.as_ref()).await.context(Io {
+                        path: path_src.as_ref(),
+                    })? != dst_crc32c
+                {
+                    log::trace!("Crc32c mismatch");
+                    Ok(Upload::Content {
+                        if_generation_match,
+                    })
+                } else if !properties.storage_class_stored_in(&object) {
+                    log::trace!("Storage class mismatch");
+                    // rewritten in place, attributes and properties are updated after that
+                    Ok(Upload::Copy {
+                        if_generation_match: Some(object.generation),
+                        object: Box::new(object),
+                    })
+                } else if !attrs.stored_in(object.metadata.as_ref()) {
+                    log::trace!("Attributes mismatch");
+                    Ok(Upload::Attrs(Box::new(object)))
+                } else if !properties.stored_in(&object) {
+                    log::trace!("Metadata mismatch");
+                    Ok(Upload::Attrs(Box::new(object)))
+                } else {
+                    Ok(Upload::Skip)
+                }
             }
+            // the object must still be missing when it's uploaded
+            Err(e) if is_not_found(&e) => match reference {
+                Some(reference) => {
+                    self.should_copy_reference(path_src.as_ref(), src_len, filename, reference)
+                        .await
+                }
+                None => Ok(Upload::Content {
+                    if_generation_match: Some(0),
+                }),
+            },
+            // uploading without a precondition could overwrite a concurrent update
+            Err(e) => Err(e).context(CloudStorage {
+                object: filename.to_owned(),
+                op: OpSource::ReadObject,
+            }),
+        }
+    }
+
+    /// Properties of an object uploaded under a name relative to the synced directory
+    pub(crate) fn object_properties(&self, name: &str) -> ObjectProperties {
+        ObjectProperties::resolve(&self.object_defaults, &self.metadata_rules, name)
+    }
+
+    /// Compression rules a file is uploaded with, if it's compressed at all
+    fn gzip(&self, path: &Path) -> Option<&Gzip> {
+        self.gzip
+            .as_ref()
+            .filter(|gzip| self.encryption.is_none() && gzip.matches(path, &guess_mime_type(path)))
+    }
+
+    /// Copies the referenced object if its content is the same as the local file
+    async fn should_copy_reference(
+        &self,
+        path_src: &Path,
+        src_len: u64,
+        filename: &str,
+        reference: &Reference,
+    ) -> Result<Upload> {
+        let upload = Upload::Content {
+            if_generation_match: Some(0),
+        };
+        let name = match reference.source_name(filename) {
+            Some(name) => name,
+            None => return Ok(upload),
+        };
+        let existing = self
+            .client
+            .get(
+                &reference.bucket,
+                &name,
+                None,
+                self.server_encryption.as_ref(),
+            )
+            .await;
+        let object = match existing {
+            Ok(object) => object,
+            Err(_) => return Ok(upload),
+        };
+        let (reference_len, reference_crc32c) = plaintext_size_crc32c(&object);
+        if encrypted_with(&object, self.encryption.as_ref())
+            && ServerEncryption::applied_to(self.server_encryption.as_ref(), &object)
+            && is_gzipped(&object) == self.gzip(path_src).is_some()
+            && reference_len == src_len
+            && file_crc32c(path_src).await.context(Io { path: path_src })? == reference_crc32c
+        {
+            Ok(Upload::Copy {
+                object: Box::new(object),
+                if_generation_match: Some(0),
+            })
         } else {
-            // cloud-sync-rs don't provide semantic errors, so on any error we assume here that file does not exists in a bucket
-            Ok(true)
+            Ok(upload)
         }
     }
 }
 
-pub(crate) trait ToStrWrap {
-    fn to_str_wrap(&self) -> Result<&str>;
+fn guess_mime_type(path: &Path) -> mime::Mime {
+    mime_guess::from_path(path).first_or(mime::APPLICATION_OCTET_STREAM)
 }
 
-impl<P: AsRef<Path>> ToStrWrap for P {
-    fn to_str_wrap(&self) -> Result<&str> {
-        self.as_ref().to_str().ok_or(Error::Other {
-            message: "Can't convert Path to &str, should never happen, please report an issue",
-        })
-    }
+enum Upload {
+    Skip,
+    /// Content matches but stored attributes or properties are outdated
+    Attrs(Box<Object>),
+    /// `if_generation_match` is the generation the decision was based on,
+    /// `Some(0)` if the object didn't exist
+    Content {
+        if_generation_match: Option<i64>,
+    },
+    /// The same content is already stored in another object,
+    /// or in the object itself with another storage class,
+    /// `if_generation_match` is the same as for [Upload::Content]
+    Copy {
+        object: Box<Object>,
+        if_generation_match: Option<i64>,
+    },
 }
@@ -0,0 +1,270 @@
+use crate::error::*;
+use crate::Result;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+/// How local file names map to object names
+///
+/// Object names are always UTF-8 while Linux file names are arbitrary bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncoding {
+    /// Names are used verbatim, syncing a file name which is not valid UTF-8 fails
+    #[default]
+    Utf8,
+    /// Bytes which are not valid UTF-8 are escaped as `%XX`, and so is `%` itself (`%25`),
+    /// which makes the mapping reversible. Object names containing `%` are therefore
+    /// different from the ones produced with [NameEncoding::Utf8]
+    Escape,
+}
+
+impl NameEncoding {
+    /// Converts a local file name or relative path to an object name
+    pub(crate) fn encode<'a>(&self, name: &'a OsStr) -> Result<Cow<'a, str>> {
+        match self {
+            Self::Utf8 => name
+                .to_str()
+                .map(Cow::Borrowed)
+                .ok_or_else(|| Error::NonUtf8Path {
+                    path: PathBuf::from(name),
+                }),
+            Self::Escape => escape(name),
+        }
+    }
+
+    /// Converts an object name or its part back to a local path
+    pub(crate) fn decode(&self, name: &str) -> Result<PathBuf> {
+        match self {
+            Self::Utf8 => Ok(PathBuf::from(name)),
+            Self::Escape => unescape(name).map(PathBuf::from),
+        }
+    }
+}
+
+/// Converts an object name relative to the synced prefix to a relative local path
+///
+/// Empty and `.` segments are dropped, so `a//./b` becomes `a/b`,
+/// names which would escape the destination directory are rejected
+pub(crate) fn local_relative_path(
+    encoding: NameEncoding,
+    name: &str,
+) -> Result<PathBuf, NameProblem> {
+    if name.starts_with('/') {
+        return Err(NameProblem::Absolute);
+    }
+    let mut path = PathBuf::new();
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(NameProblem::ParentTraversal),
+            _ => {}
+        }
+        if segment.contains('\0') {
+            return Err(NameProblem::InvalidSegment {
+                segment: segment.to_owned(),
+            });
+        }
+        let decoded = encoding
+            .decode(segment)
+            .map_err(|_| NameProblem::InvalidSegment {
+                segment: segment.to_owned(),
+            })?;
+        // a decoded segment may still turn out to be a drive prefix, a separator or `..`
+        let mut components = decoded.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(component)), None) => path.push(component),
+            _ => {
+                return Err(NameProblem::InvalidSegment {
+                    segment: segment.to_owned(),
+                })
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Whether a symlink at `link` under `root` pointing to `target` leads outside of `root`
+///
+/// Absolute targets always do, relative ones are resolved without following other symlinks
+pub(crate) fn symlink_escapes(root: &Path, link: &Path, target: &Path) -> bool {
+    if target.has_root() {
+        return true;
+    }
+    let mut depth = match link.parent().map(|parent| parent.strip_prefix(root)) {
+        Some(Ok(parent)) => parent.components().count(),
+        _ => return true,
+    };
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Local paths claimed by objects during a single download,
+/// detects objects that would map to the same path or to both a file and a directory
+#[derive(Debug, Default)]
+pub(crate) struct LocalNames {
+    files: HashMap<PathBuf, String>,
+    dirs: HashMap<PathBuf, String>,
+    pub(crate) rejected: Vec<(String, NameProblem)>,
+}
+
+impl LocalNames {
+    /// Returns the relative local path of an object,
+    /// or `None` if the object cannot be represented locally, recording the reason
+    pub(crate) fn claim(
+        &mut self,
+        object_name: &str,
+        relative: Result<PathBuf, NameProblem>,
+        is_dir: bool,
+    ) -> Option<PathBuf> {
+        match relative.and_then(|relative| self.check(object_name, relative, is_dir)) {
+            Ok(relative) => Some(relative),
+            Err(problem) => {
+                log::warn!("Skip gs object {:?}: {}", object_name, problem);
+                self.rejected.push((object_name.to_owned(), problem));
+                None
+            }
+        }
+    }
+
+    fn check(
+        &mut self,
+        object_name: &str,
+        relative: PathBuf,
+        is_dir: bool,
+    ) -> Result<PathBuf, NameProblem> {
+        let collision = |with: &String| NameProblem::Collision { with: with.clone() };
+        if let Some(with) = self.files.get(&relative) {
+            return Err(collision(with));
+        }
+        if !is_dir {
+            if relative.as_os_str().is_empty() {
+                return Err(NameProblem::Empty);
+            }
+            if let Some(with) = self.dirs.get(&relative) {
+                return Err(collision(with));
+            }
+        }
+        for ancestor in relative.ancestors().skip(1) {
+            if let Some(with) = self.files.get(ancestor) {
+                return Err(collision(with));
+            }
+        }
+
+        let dirs = if is_dir {
+            relative.ancestors()
+        } else {
+            self.files.insert(relative.clone(), object_name.to_owned());
+            let mut ancestors = relative.ancestors();
+            ancestors.next();
+            ancestors
+        };
+        for dir in dirs {
+            self.dirs
+                .entry(dir.to_owned())
+                .or_insert_with(|| object_name.to_owned());
+        }
+        Ok(relative)
+    }
+}
+
+/// Appends a name to an object prefix the same way [Path::join] does
+pub(crate) fn join_object_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else if prefix.ends_with('/') {
+        format!("{}{}", prefix, name)
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+fn push_escaped_str(escaped: &mut String, valid: &str) {
+    for c in valid.chars() {
+        if c == '%' {
+            escaped.push_str("%25");
+        } else {
+            escaped.push(c);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn escape(name: &OsStr) -> Result<Cow<'_, str>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut bytes = name.as_bytes();
+    let mut escaped = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                push_escaped_str(&mut escaped, valid);
+                return Ok(Cow::Owned(escaped));
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                // valid_up_to guarantees this part is valid
+                push_escaped_str(&mut escaped, std::str::from_utf8(valid).unwrap_or_default());
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                for byte in &rest[..invalid_len] {
+                    escaped.push_str(&format!("%{:02X}", byte));
+                }
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn escape(name: &OsStr) -> Result<Cow<'_, str>> {
+    let valid = name.to_str().ok_or_else(|| Error::NonUtf8Path {
+        path: PathBuf::from(name),
+    })?;
+    let mut escaped = String::with_capacity(valid.len());
+    push_escaped_str(&mut escaped, valid);
+    Ok(Cow::Owned(escaped))
+}
+
+fn unescape_bytes(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = hex {
+                unescaped.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    unescaped
+}
+
+#[cfg(unix)]
+fn unescape(name: &str) -> Result<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(unescape_bytes(name)))
+}
+
+#[cfg(not(unix))]
+fn unescape(name: &str) -> Result<OsString> {
+    String::from_utf8(unescape_bytes(name))
+        .map(OsString::from)
+        .map_err(|_| Error::NonUtf8Path {
+            path: PathBuf::from(name),
+        })
+}
@@ -0,0 +1,205 @@
+//! Local mirror kept current by object change notifications
+
+use crate::api::*;
+use crate::error::*;
+use crate::gcs::{check_no_symlink_parents, GcsSource};
+use crate::names::*;
+use crate::Result;
+use futures::stream::{Stream, StreamExt};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Kind of an object change, `eventType` attribute of a notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    /// A new object or a new generation of an existing one was created
+    Finalize,
+    /// Metadata of an existing object changed
+    MetadataUpdate,
+    /// An object was deleted, or replaced by a new generation
+    Delete,
+    /// The live generation of an object became noncurrent
+    Archive,
+}
+
+impl EventType {
+    fn parse(event_type: &str) -> Option<Self> {
+        match event_type {
+            "OBJECT_FINALIZE" => Some(Self::Finalize),
+            "OBJECT_METADATA_UPDATE" => Some(Self::MetadataUpdate),
+            "OBJECT_DELETE" => Some(Self::Delete),
+            "OBJECT_ARCHIVE" => Some(Self::Archive),
+            _ => None,
+        }
+    }
+}
+
+/// An object change notification, as published by Cloud Storage to Pub/Sub
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub event_type: EventType,
+    pub bucket: String,
+    pub object: String,
+    pub generation: i64,
+    /// Generation replacing the deleted or archived one, `None` if it was removed
+    pub overwritten_by_generation: Option<i64>,
+}
+
+impl Notification {
+    /// Reads a notification from Pub/Sub message attributes,
+    /// the message payload isn't needed
+    pub fn from_attributes(attributes: &HashMap<String, String>) -> Result<Self> {
+        let attribute = |name: &'static str| {
+            attributes
+                .get(name)
+                .ok_or(Error::InvalidNotification { reason: name })
+        };
+        let generation = |name: &'static str, value: &str| {
+            value
+                .parse()
+                .map_err(|_| Error::InvalidNotification { reason: name })
+        };
+        let event_type =
+            EventType::parse(attribute("eventType")?).ok_or(Error::InvalidNotification {
+                reason: "eventType",
+            })?;
+        let overwritten_by_generation = match attributes.get("overwrittenByGeneration") {
+            Some(value) => Some(generation("overwrittenByGeneration", value)?),
+            None => None,
+        };
+        Ok(Self {
+            event_type,
+            bucket: attribute("bucketId")?.clone(),
+            object: attribute("objectId")?.clone(),
+            generation: generation("objectGeneration", attribute("objectGeneration")?)?,
+            overwritten_by_generation,
+        })
+    }
+}
+
+impl GcsSource {
+    /// Applies object change notifications of [bucket_src]/[path_src] to a local path
+    /// synced by [GcsSource::to_local] until the stream ends
+    ///
+    /// Failures to apply a notification are logged and the next one is applied,
+    /// use [GcsSource::apply_notification] to handle them.
+    /// Returns count of changed local files and directories
+    pub async fn consume_notifications(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        dst_dir: impl AsRef<Path>,
+        notifications: impl Stream<Item = Notification>,
+    ) -> usize {
+        let dst_dir = dst_dir.as_ref();
+        notifications
+            .fold(0, |count, notification| async move {
+                match self
+                    .apply_notification(bucket_src, path_src, dst_dir, &notification)
+                    .await
+                {
+                    Ok(changed) => count + changed,
+                    Err(e) => {
+                        log::error!("Can't apply {:?}: {}", notification, e);
+                        count
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Applies an object change notification to a local path synced by [GcsSource::to_local]
+    ///
+    /// Notifications of other buckets and paths are ignored. Notifications may come
+    /// late and out of order, so the object is synced as it is now: created and updated
+    /// objects are downloaded like by [GcsSource::to_local], local files of removed
+    /// ones are deleted and directories only if they're empty.
+    /// Returns count of changed local files and directories
+    pub async fn apply_notification(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        dst_dir: impl AsRef<Path>,
+        notification: &Notification,
+    ) -> Result<usize> {
+        let name = &notification.object;
+        let stripped = match name.strip_prefix(&join_object_name(path_src, "")) {
+            Some(stripped) if notification.bucket == bucket_src => stripped,
+            _ => {
+                log::trace!("Skip notification of gs://{}/{}", notification.bucket, name);
+                return Ok(0);
+            }
+        };
+        if notification.overwritten_by_generation.is_some() {
+            // the replacing generation has a notification of its own
+            return Ok(0);
+        }
+        if self.filter.excludes(stripped) {
+            log::trace!("Skip filtered {:?}", name);
+            return Ok(0);
+        }
+        let relative = match local_relative_path(self.names, stripped) {
+            Ok(relative) => relative,
+            Err(problem) => {
+                return Err(Error::UnrepresentableNames {
+                    names: vec![(name.clone(), problem)],
+                })
+            }
+        };
+        let path_dst = dst_dir.as_ref().join(relative);
+
+        let current = self
+            .client
+            .get(bucket_src, name, None, self.server_encryption.as_ref())
+            .await;
+        match current {
+            Ok(object) => match self
+                .restore_entry(&object, dst_dir.as_ref(), &path_dst)
+                .await?
+            {
+                Some(restored) => Ok(restored),
+                None => self.download_object(bucket_src, &path_dst, &object).await,
+            },
+            Err(e) if is_not_found(&e) && self.dry_run => {
+                check_no_symlink_parents(dst_dir.as_ref(), &path_dst)?;
+                let exists = tokio::fs::symlink_metadata(&path_dst).await.is_ok();
+                if exists {
+                    log::info!("Would remove {:?}", path_dst);
+                }
+                Ok(exists as usize)
+            }
+            Err(e) if is_not_found(&e) => remove_local(dst_dir.as_ref(), &path_dst).await,
+            Err(e) => Err(e).context(CloudStorage {
+                object: name.clone(),
+                op: OpSource::ReadObject,
+            }),
+        }
+    }
+}
+
+/// Removes the local file or empty directory of a removed object under `root`
+async fn remove_local(root: &Path, path: &Path) -> Result<usize> {
+    // a symlinked parent would lead the removal outside of `root`
+    check_no_symlink_parents(root, path)?;
+    let is_dir = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata.is_dir(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).context(Io { path }),
+    };
+    let removed = if is_dir {
+        tokio::fs::remove_dir(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    };
+    match removed {
+        Ok(()) => {
+            log::trace!("Removed {:?}", path);
+            Ok(1)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        // still has files of other objects
+        Err(_) if is_dir => Ok(0),
+        Err(e) => Err(e).context(Io { path }),
+    }
+}
@@ -0,0 +1,184 @@
+use crate::error::*;
+use crate::Result;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs::{File, FileTimes, Metadata};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Custom metadata keys used by `gsutil -P`, so objects synced by either tool
+/// can be restored by the other
+pub(crate) const MTIME_KEY: &str = "goog-reserved-file-mtime";
+pub(crate) const ATIME_KEY: &str = "goog-reserved-file-atime";
+pub(crate) const MODE_KEY: &str = "goog-reserved-posix-mode";
+
+/// Only permission bits are synced, setuid, setgid and sticky bits stored in a bucket
+/// could otherwise turn restored files into privileged executables
+const PERMISSION_BITS: u32 = 0o777;
+
+/// Which file attributes are stored in object metadata on upload
+/// and restored on download
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Preserve {
+    pub(crate) times: bool,
+    pub(crate) mode: bool,
+}
+
+impl Preserve {
+    pub(crate) fn any(&self) -> bool {
+        self.times || self.mode
+    }
+}
+
+/// File attributes as stored in `goog-reserved-*` object metadata,
+/// times are whole seconds since the unix epoch, mode is stored in octal
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PosixAttrs {
+    pub(crate) mtime: Option<i64>,
+    pub(crate) atime: Option<i64>,
+    pub(crate) mode: Option<u32>,
+}
+
+impl PosixAttrs {
+    pub(crate) fn from_metadata(metadata: &Metadata, preserve: Preserve) -> Self {
+        let mut attrs = Self::default();
+        if preserve.times {
+            attrs.mtime = metadata.modified().ok().map(unix_seconds);
+            attrs.atime = metadata.accessed().ok().map(unix_seconds);
+        }
+        if preserve.mode {
+            attrs.mode = file_mode(metadata);
+        }
+        attrs
+    }
+
+    pub(crate) fn from_object_metadata(
+        metadata: Option<&HashMap<String, String>>,
+        preserve: Preserve,
+    ) -> Self {
+        let mut attrs = Self::default();
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return attrs,
+        };
+        if preserve.times {
+            attrs.mtime = metadata.get(MTIME_KEY).and_then(|v| v.parse().ok());
+            attrs.atime = metadata.get(ATIME_KEY).and_then(|v| v.parse().ok());
+        }
+        if preserve.mode {
+            attrs.mode = metadata
+                .get(MODE_KEY)
+                .and_then(|v| u32::from_str_radix(v, 8).ok())
+                .map(|mode| mode & PERMISSION_BITS);
+        }
+        attrs
+    }
+
+    /// Writes attributes into object metadata, keeping unrelated keys
+    pub(crate) fn merge_into(&self, metadata: &mut HashMap<String, String>) {
+        if let Some(mtime) = self.mtime {
+            metadata.insert(MTIME_KEY.to_owned(), mtime.to_string());
+        }
+        if let Some(atime) = self.atime {
+            metadata.insert(ATIME_KEY.to_owned(), atime.to_string());
+        }
+        if let Some(mode) = self.mode {
+            metadata.insert(MODE_KEY.to_owned(), format!("{:o}", mode));
+        }
+    }
+
+    /// Whether the object metadata already holds these attributes
+    ///
+    /// atime is ignored since reading a file for a crc32c comparison updates it
+    pub(crate) fn stored_in(&self, metadata: Option<&HashMap<String, String>>) -> bool {
+        let stored = |key: &str, value: Option<String>| match value {
+            Some(value) => metadata
+                .and_then(|m| m.get(key))
+                .is_some_and(|v| v == &value),
+            None => true,
+        };
+        stored(MTIME_KEY, self.mtime.map(|mtime| mtime.to_string()))
+            && stored(MODE_KEY, self.mode.map(|mode| format!("{:o}", mode)))
+    }
+
+    /// Applies attributes to a local file, missing ones are left untouched
+    pub(crate) fn apply(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.apply_times(path)?;
+        if let Some(mode) = self.mode {
+            set_file_mode(path, mode)?;
+            log::trace!("Restored mode {:o} of {:?}", mode, path);
+        }
+        Ok(())
+    }
+
+    fn apply_times(&self, path: &Path) -> Result<()> {
+        if self.mtime.is_none() && self.atime.is_none() {
+            return Ok(());
+        }
+        let mut times = FileTimes::new();
+        if let Some(mtime) = self.mtime {
+            times = times.set_modified(from_unix_seconds(mtime));
+        }
+        if let Some(atime) = self.atime {
+            times = times.set_accessed(from_unix_seconds(atime));
+        }
+        // owner may set times without write permission, so a read-only file is fine here
+        File::open(path)
+            .and_then(|file| file.set_times(times))
+            .context(Io { path })?;
+        log::trace!("Restored times of {:?}", path);
+        Ok(())
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
+fn from_unix_seconds(seconds: i64) -> SystemTime {
+    if seconds >= 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    }
+}
+
+/// Makes an existing read-only file writable by its owner so it can be overwritten
+/// by a download which restores the stored mode afterwards
+pub(crate) fn make_writable(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    if let Ok(metadata) = path.metadata() {
+        if metadata.permissions().readonly() {
+            if let Some(mode) = file_mode(&metadata) {
+                set_file_mode(path, mode | 0o200)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & PERMISSION_BITS)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).context(Io { path })
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
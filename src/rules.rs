@@ -0,0 +1,256 @@
+//! Object properties assigned by glob rules on upload, and glob filters of synced names
+
+use crate::error::*;
+use crate::Result;
+use cloud_storage::object::Object;
+use globset::{GlobBuilder, GlobMatcher};
+use snafu::ResultExt;
+use std::collections::HashMap;
+
+/// Storage class objects are written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StorageClass {
+    Standard,
+    Nearline,
+    Coldline,
+    Archive,
+}
+
+impl StorageClass {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Standard => "STANDARD",
+            Self::Nearline => "NEARLINE",
+            Self::Coldline => "COLDLINE",
+            Self::Archive => "ARCHIVE",
+        }
+    }
+}
+
+/// Properties set on objects uploaded from files matching a glob
+///
+/// Globs are matched against file paths relative to the synced directory, `/` separated.
+/// A glob without `/` matches file names at any depth, e.g. `*.html`,
+/// otherwise the whole relative path, e.g. `assets/**`.
+///
+/// Only properties set by a rule are compared and updated,
+/// others are left as they are stored.
+#[derive(Debug, Clone)]
+pub struct MetadataRule {
+    glob: NameGlob,
+    properties: ObjectProperties,
+}
+
+impl MetadataRule {
+    pub fn new(glob: &str) -> Result<Self> {
+        Ok(Self {
+            glob: NameGlob::new(glob)?,
+            properties: ObjectProperties::default(),
+        })
+    }
+
+    /// Overrides the content type guessed from the file extension
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.properties.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Sets `Cache-Control`, e.g. `no-cache` or `public, max-age=31536000`
+    pub fn with_cache_control(mut self, cache_control: impl Into<String>) -> Self {
+        self.properties.cache_control = Some(cache_control.into());
+        self
+    }
+
+    /// Sets `Content-Disposition`, e.g. `attachment`
+    pub fn with_content_disposition(mut self, content_disposition: impl Into<String>) -> Self {
+        self.properties.content_disposition = Some(content_disposition.into());
+        self
+    }
+
+    /// Sets `Content-Language`, e.g. `en`
+    pub fn with_content_language(mut self, content_language: impl Into<String>) -> Self {
+        self.properties.content_language = Some(content_language.into());
+        self
+    }
+
+    /// Sets a custom metadata key
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Writes objects with a storage class, objects stored with another one are rewritten
+    pub fn with_storage_class(mut self, storage_class: StorageClass) -> Self {
+        self.properties.storage_class = Some(storage_class);
+        self
+    }
+
+    /// Places or releases a temporary hold
+    pub fn with_temporary_hold(mut self, hold: bool) -> Self {
+        self.properties.temporary_hold = Some(hold);
+        self
+    }
+
+    /// Places or releases an event-based hold
+    pub fn with_event_based_hold(mut self, hold: bool) -> Self {
+        self.properties.event_based_hold = Some(hold);
+        self
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.glob.matches(name)
+    }
+}
+
+/// Files and objects to sync, globs are matched like [MetadataRule] globs
+///
+/// Everything is synced by default. Once a glob is included only files matching
+/// one of the included globs are synced, excluded globs take precedence.
+/// Directories matching an excluded glob are skipped with everything inside,
+/// included globs aren't matched against directories.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<NameGlob>,
+    exclude: Vec<NameGlob>,
+}
+
+impl Filter {
+    /// Syncs files matching the glob, e.g. `*.html` or `assets/**`
+    pub fn with_include(mut self, glob: &str) -> Result<Self> {
+        self.include.push(NameGlob::new(glob)?);
+        Ok(self)
+    }
+
+    /// Skips files and directories matching the glob, e.g. `*.tmp` or `.git`
+    pub fn with_exclude(mut self, glob: &str) -> Result<Self> {
+        self.exclude.push(NameGlob::new(glob)?);
+        Ok(self)
+    }
+
+    /// Whether a relative name is filtered out, directory names end with `/`
+    pub(crate) fn excludes(&self, name: &str) -> bool {
+        let (name, is_dir) = match name.strip_suffix('/') {
+            Some(dir) => (dir, true),
+            None => (name, false),
+        };
+        let excluded = |name: &str| self.exclude.iter().any(|glob| glob.matches(name));
+        let in_excluded_dir = name
+            .match_indices('/')
+            .any(|(index, _)| excluded(&name[..index]));
+        if in_excluded_dir || excluded(name) {
+            return true;
+        }
+        !is_dir && !self.include.is_empty() && !self.include.iter().any(|glob| glob.matches(name))
+    }
+}
+
+/// A glob matching file names, or whole relative paths if it contains `/`
+#[derive(Debug, Clone)]
+struct NameGlob {
+    glob: GlobMatcher,
+    file_name_only: bool,
+}
+
+impl NameGlob {
+    fn new(glob: &str) -> Result<Self> {
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .context(InvalidGlob { pattern: glob })?
+            .compile_matcher();
+        Ok(Self {
+            glob: matcher,
+            file_name_only: !glob.contains('/'),
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        if self.file_name_only {
+            let file_name = name.rsplit('/').next().unwrap_or(name);
+            self.glob.is_match(file_name)
+        } else {
+            self.glob.is_match(name)
+        }
+    }
+}
+
+/// Object properties a file is uploaded with, also the body of metadata-only updates
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ObjectProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_control: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_disposition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content_language: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) metadata: HashMap<String, String>,
+    /// changed by rewriting objects, it can't be patched
+    #[serde(skip)]
+    pub(crate) storage_class: Option<StorageClass>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temporary_hold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) event_based_hold: Option<bool>,
+}
+
+impl ObjectProperties {
+    /// `defaults` overridden by all rules matching a relative name,
+    /// later rules override earlier ones
+    pub(crate) fn resolve(defaults: &Self, rules: &[MetadataRule], name: &str) -> Self {
+        let mut properties = defaults.clone();
+        for rule in rules.iter().filter(|rule| rule.matches(name)) {
+            let set = &rule.properties;
+            override_with(&mut properties.content_type, &set.content_type);
+            override_with(&mut properties.cache_control, &set.cache_control);
+            override_with(
+                &mut properties.content_disposition,
+                &set.content_disposition,
+            );
+            override_with(&mut properties.content_language, &set.content_language);
+            properties.metadata.extend(set.metadata.clone());
+            override_with(&mut properties.storage_class, &set.storage_class);
+            override_with(&mut properties.temporary_hold, &set.temporary_hold);
+            override_with(&mut properties.event_based_hold, &set.event_based_hold);
+        }
+        properties
+    }
+
+    /// Whether the object already has all properties set here, except the storage class
+    pub(crate) fn stored_in(&self, object: &Object) -> bool {
+        let stored = |set: &Option<String>, stored: &Option<String>| set.is_none() || set == stored;
+        // holds are reported only when they were ever set
+        let held = |set: Option<bool>, stored: Option<bool>| {
+            set.is_none_or(|set| set == stored.unwrap_or(false))
+        };
+        stored(&self.content_type, &object.content_type)
+            && stored(&self.cache_control, &object.cache_control)
+            && stored(&self.content_disposition, &object.content_disposition)
+            && stored(&self.content_language, &object.content_language)
+            && self.metadata.iter().all(|(key, value)| {
+                object
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get(key))
+                    == Some(value)
+            })
+            && held(self.temporary_hold, object.temporary_hold)
+            && held(self.event_based_hold, object.event_based_hold)
+    }
+
+    /// Whether the object is stored with the storage class set here
+    pub(crate) fn storage_class_stored_in(&self, object: &Object) -> bool {
+        self.storage_class
+            .is_none_or(|storage_class| storage_class.as_str() == object.storage_class)
+    }
+}
+
+fn override_with<T: Clone>(value: &mut Option<T>, set: &Option<T>) {
+    if set.is_some() {
+        value.clone_from(set);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::api::*;
+use crate::error::*;
+use crate::gcs::GcsSource;
+use crate::local::{LocalSource, Reference};
+use crate::names::*;
+use crate::Result;
+use chrono::{DateTime, Datelike, NaiveDateTime, SubsecRound, Utc};
+use std::path::Path;
+
+/// Snapshot prefixes are named after their UTC creation time, e.g. `20210131T235959.123456Z`,
+/// so backups made within the same second get prefixes of their own
+const SNAPSHOT_NAME_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// Backups of a local directory as timestamped snapshots under a bucket prefix
+///
+/// Every snapshot is a complete copy of the directory under `[prefix]/[time]/`,
+/// files unchanged since the previous snapshot are copied from it server-side
+/// instead of being uploaded again.
+#[derive(Debug)]
+pub struct Snapshots {
+    local: LocalSource,
+    gcs: GcsSource,
+}
+
+/// A snapshot stored under `prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub time: DateTime<Utc>,
+    /// object name prefix of the snapshot content, without a trailing `/`
+    pub prefix: String,
+}
+
+/// Which snapshots survive pruning
+///
+/// A snapshot is kept if any of the rules keeps it,
+/// a retention without rules keeps everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retention {
+    keep_last: usize,
+    daily: usize,
+    weekly: usize,
+}
+
+impl Retention {
+    /// Keeps the `count` newest snapshots
+    pub fn with_keep_last(mut self, count: usize) -> Self {
+        self.keep_last = count;
+        self
+    }
+
+    /// Keeps the newest snapshot of each of the last `days` days having snapshots
+    pub fn with_daily(mut self, days: usize) -> Self {
+        self.daily = days;
+        self
+    }
+
+    /// Keeps the newest snapshot of each of the last `weeks` ISO weeks having snapshots
+    pub fn with_weekly(mut self, weeks: usize) -> Self {
+        self.weekly = weeks;
+        self
+    }
+
+    /// Snapshots not kept by any rule, newest first
+    pub(crate) fn expired(&self, snapshots: &[Snapshot]) -> Vec<Snapshot> {
+        if self.keep_last == 0 && self.daily == 0 && self.weekly == 0 {
+            return vec![];
+        }
+        let mut snapshots = snapshots.to_vec();
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.time));
+
+        let mut keep = vec![false; snapshots.len()];
+        for kept in keep.iter_mut().take(self.keep_last) {
+            *kept = true;
+        }
+        Self::keep_periods(&snapshots, self.daily, &mut keep, |time| time.date_naive());
+        Self::keep_periods(&snapshots, self.weekly, &mut keep, |time| time.iso_week());
+
+        snapshots
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, kept)| !kept)
+            .map(|(snapshot, _)| snapshot)
+            .collect()
+    }
+
+    /// Keeps the first, i.e. the newest, snapshot of each of `count` periods
+    fn keep_periods<P: PartialEq>(
+        newest_first: &[Snapshot],
+        count: usize,
+        keep: &mut [bool],
+        period: impl Fn(&DateTime<Utc>) -> P,
+    ) {
+        let mut last = None;
+        let mut periods = 0;
+        for (snapshot, kept) in newest_first.iter().zip(keep.iter_mut()) {
+            if periods == count {
+                break;
+            }
+            let current = Some(period(&snapshot.time));
+            if current != last {
+                *kept = true;
+                periods += 1;
+                last = current;
+            }
+        }
+    }
+}
+
+impl Snapshots {
+    /// `local` source provides the options used for uploads,
+    /// `gcs` the ones used for listing and restores
+    pub fn new(local: LocalSource, gcs: GcsSource) -> Self {
+        Self { local, gcs }
+    }
+
+    /// Stores local file or directory as a new snapshot under [bucket]/[prefix]
+    pub async fn backup(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<Snapshot> {
+        // the name has microseconds, so has the time parsed back from it
+        let time = Utc::now().trunc_subsecs(6);
+        let snapshot = Snapshot {
+            prefix: join_object_name(prefix, &time.format(SNAPSHOT_NAME_FORMAT).to_string()),
+            time,
+        };
+        let existing = self.list(bucket, prefix).await?;
+        // files deleted since the other backup would stay in the shared prefix
+        if existing.iter().any(|other| other.prefix == snapshot.prefix) {
+            return Err(Error::PreconditionFailed {
+                bucket: bucket.to_owned(),
+                object: snapshot.prefix,
+            });
+        }
+        let reference = existing
+            .into_iter()
+            .rfind(|previous| previous.time < snapshot.time)
+            .map(|previous| Reference {
+                bucket: bucket.to_owned(),
+                src_prefix: format!("{}/", previous.prefix),
+                dst_prefix: format!("{}/", snapshot.prefix),
+            });
+        log::trace!(
+            "Creating snapshot gs://{}/{} referencing {:?}",
+            bucket,
+            snapshot.prefix,
+            reference
+        );
+        let count = self
+            .local
+            .to_gcs_referencing(path_src, bucket, &snapshot.prefix, reference)
+            .await?;
+        log::trace!("Snapshot {} stored, {} objects", snapshot.prefix, count);
+        Ok(snapshot)
+    }
+
+    /// Snapshots stored under [bucket]/[prefix], oldest first
+    pub async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<Snapshot>> {
+        let parent = join_object_name(prefix, "");
+        let mut snapshots = vec![];
+        for child in self.gcs.list_prefixes(bucket, &parent).await? {
+            let child = child.trim_end_matches('/');
+            let name = child.strip_prefix(&parent).unwrap_or(child);
+            match NaiveDateTime::parse_from_str(name, SNAPSHOT_NAME_FORMAT) {
+                Ok(time) => snapshots.push(Snapshot {
+                    time: time.and_utc(),
+                    prefix: child.to_owned(),
+                }),
+                Err(_) => log::trace!("Skip gs://{}/{}, not a snapshot", bucket, child),
+            }
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+        Ok(snapshots)
+    }
+
+    /// Deletes snapshots not kept by the retention, returns the deleted ones
+    pub async fn prune(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        retention: &Retention,
+    ) -> Result<Vec<Snapshot>> {
+        let expired = retention.expired(&self.list(bucket, prefix).await?);
+        for snapshot in &expired {
+            log::trace!("Deleting snapshot gs://{}/{}", bucket, snapshot.prefix);
+            let objects = self
+                .gcs
+                .list_objects(bucket, &format!("{}/", snapshot.prefix), false)
+                .await?;
+            for object in objects {
+                let deleted = self
+                    .gcs
+                    .client
+                    .delete(bucket, &object.name, Some(object.generation))
+                    .await;
+                check_precondition(deleted, bucket, &object.name, OpSource::DeleteObject)?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Downloads snapshot content to a local directory
+    ///
+    /// Returns actual downloads count
+    pub async fn restore(
+        &self,
+        bucket: &str,
+        snapshot: &Snapshot,
+        dst_dir: impl AsRef<Path>,
+    ) -> Result<usize> {
+        self.gcs.to_local(bucket, &snapshot.prefix, dst_dir).await
+    }
+}
@@ -0,0 +1,363 @@
+//! Single entry point for syncs between local paths and buckets
+
+use crate::api::Client;
+use crate::auth::Credentials;
+use crate::error::*;
+use crate::gcs::GcsSource;
+use crate::job::Location;
+use crate::local::{Comparison, LocalSource};
+use crate::rules::Filter;
+use crate::Result;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A file or object synced, reported to the [SyncBuilder::with_progress] callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// File content, a symlink or an empty directory was uploaded, `bytes` as stored
+    Uploaded {
+        path: PathBuf,
+        bucket: String,
+        object: String,
+        bytes: u64,
+    },
+    /// Object content was downloaded to a file, `bytes` as stored
+    Downloaded {
+        bucket: String,
+        object: String,
+        path: PathBuf,
+        bytes: u64,
+    },
+    /// An object was copied within Cloud Storage instead of being uploaded
+    Copied {
+        bucket: String,
+        object: String,
+        bucket_dst: String,
+        object_dst: String,
+    },
+    /// Object properties or file attributes were updated, content was up to date
+    Updated { bucket: String, object: String },
+}
+
+/// Callback receiving [SyncEvent]s, shared by clones
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<dyn Fn(&SyncEvent) + Send + std::marker::Sync>);
+
+impl Progress {
+    pub(crate) fn report(&self, event: SyncEvent) {
+        (self.0)(&event)
+    }
+}
+
+/// Reports an event built only if there's a callback to receive it
+pub(crate) fn report(progress: Option<&Progress>, event: impl FnOnce() -> SyncEvent) {
+    if let Some(progress) = progress {
+        progress.report(event());
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// How syncs failing with a transient error, see [Error::is_transient], are retried
+///
+/// The whole sync is run again, files and objects synced by the failed attempt
+/// are skipped as up to date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    attempts: usize,
+    backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retry {
+    /// Fails on the first error
+    pub fn never() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::default(),
+        }
+    }
+
+    /// Attempts including the first one, 3 by default
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Delay before the first retry, doubled for each next one, a second by default
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub(crate) async fn run<F, T>(&self, mut attempt: impl FnMut() -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.backoff;
+        for _ in 1..self.attempts {
+            match attempt().await {
+                Err(e) if e.is_transient() => {
+                    log::warn!("Sync failed, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                done => return done,
+            }
+        }
+        attempt().await
+    }
+}
+
+/// Syncs local paths and buckets in any direction
+///
+/// ```no_run
+/// # use cloud_storage_sync::*;
+/// # async fn run() -> Result<(), error::Error> {
+/// let sync = Sync::builder()
+///     .with_concurrency(8)
+///     .with_filter(Filter::default().with_exclude("*.tmp")?)
+///     .with_progress(|event| println!("{:?}", event))
+///     .build();
+/// let src = "./site".parse()?;
+/// let dst = "gs://my-bucket/site".parse()?;
+/// sync.sync(&src, &dst).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Sync {
+    local: LocalSource,
+    gcs: GcsSource,
+    retry: Retry,
+}
+
+impl Sync {
+    /// A sync with default options, overwriting local files and directories
+    /// in the way of downloads if `force_overwrite` is set
+    pub fn new(force_overwrite: bool) -> Self {
+        Self::builder()
+            .with_force_overwrite(force_overwrite)
+            .build()
+    }
+
+    pub fn builder() -> SyncBuilder {
+        SyncBuilder::default()
+    }
+
+    /// Syncs `src` to `dst`, at least one of them must be in a bucket.
+    /// Returns operations count
+    pub async fn sync(&self, src: &Location, dst: &Location) -> Result<usize> {
+        match (src, dst) {
+            (Location::Local(path), Location::Gcs { bucket, prefix }) => {
+                self.sync_local_to_gcs(path, bucket, prefix).await
+            }
+            (Location::Gcs { bucket, prefix }, Location::Local(path)) => {
+                self.sync_gcs_to_local(bucket, prefix, path).await
+            }
+            (
+                Location::Gcs { bucket, prefix },
+                Location::Gcs {
+                    bucket: bucket_dst,
+                    prefix: prefix_dst,
+                },
+            ) => {
+                self.sync_gcs_to_gcs(bucket, prefix, bucket_dst, prefix_dst)
+                    .await
+            }
+            (Location::Local(_), Location::Local(_)) => Err(Error::Other {
+                message: "source or destination must be gs://bucket/prefix",
+            }),
+        }
+    }
+
+    /// [LocalSource::to_gcs]
+    pub async fn sync_local_to_gcs(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket_dst: &str,
+        path_dst: &str,
+    ) -> Result<usize> {
+        let path_src = path_src.as_ref();
+        self.retry
+            .run(|| self.local.to_gcs(path_src, bucket_dst, path_dst))
+            .await
+    }
+
+    /// [GcsSource::to_local]
+    pub async fn sync_gcs_to_local(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        dst_dir: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let dst_dir = dst_dir.as_ref();
+        self.retry
+            .run(|| self.gcs.to_local(bucket_src, path_src, dst_dir))
+            .await
+    }
+
+    /// [GcsSource::to_gcs]
+    pub async fn sync_gcs_to_gcs(
+        &self,
+        bucket_src: &str,
+        path_src: &str,
+        bucket_dst: &str,
+        path_dst: &str,
+    ) -> Result<usize> {
+        self.retry
+            .run(|| self.gcs.to_gcs(bucket_src, path_src, bucket_dst, path_dst))
+            .await
+    }
+
+    /// Uploading side, for operations the facade doesn't cover
+    pub fn local(&self) -> &LocalSource {
+        &self.local
+    }
+
+    /// Downloading and copying side, for operations the facade doesn't cover
+    pub fn gcs(&self) -> &GcsSource {
+        &self.gcs
+    }
+}
+
+/// Options of a [Sync], new options are added here without breaking existing callers
+#[derive(Debug)]
+pub struct SyncBuilder {
+    concurrency: usize,
+    force_overwrite: bool,
+    comparison: Comparison,
+    dry_run: bool,
+    filter: Filter,
+    client: Client,
+    credentials: Option<Credentials>,
+    billing_project: Option<String>,
+    retry: Retry,
+    progress: Option<Progress>,
+}
+
+impl Default for SyncBuilder {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            force_overwrite: false,
+            comparison: Comparison::default(),
+            dry_run: false,
+            filter: Filter::default(),
+            client: Client::default(),
+            credentials: None,
+            billing_project: None,
+            retry: Retry::default(),
+            progress: None,
+        }
+    }
+}
+
+impl SyncBuilder {
+    /// Number of concurrent uploads or downloads, 4 by default
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Transfers content regardless of comparison and replaces local files
+    /// and directories in the way of downloads, disabled by default
+    pub fn with_force_overwrite(mut self, force_overwrite: bool) -> Self {
+        self.force_overwrite = force_overwrite;
+        self
+    }
+
+    /// Sets how existing files and objects are compared, [Comparison::Checksum] by default
+    pub fn with_comparison(mut self, comparison: Comparison) -> Self {
+        self.comparison = comparison;
+        self
+    }
+
+    /// Counts and logs operations without making them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Syncs only files and objects the filter doesn't exclude, everything by default
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Client both sources send requests through, a default one is shared otherwise
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Authorizes requests with the credentials instead of the service account key
+    /// at the path in `SERVICE_ACCOUNT`
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Bills requests to the project, required to sync with requester-pays buckets
+    pub fn with_billing_project(mut self, project: impl Into<String>) -> Self {
+        self.billing_project = Some(project.into());
+        self
+    }
+
+    /// Retries syncs failing with transient errors 3 times by default
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Calls `progress` for each file or object synced, possibly from concurrent transfers
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(&SyncEvent) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Progress(Arc::new(progress)));
+        self
+    }
+
+    /// Creates the [Sync] with these options
+    pub fn build(self) -> Sync {
+        let mut client = self.client;
+        if let Some(credentials) = self.credentials {
+            client = client.with_credentials(credentials);
+        }
+        if let Some(project) = self.billing_project {
+            client = client.with_billing_project(project);
+        }
+        let mut local = LocalSource::new(self.force_overwrite, self.concurrency)
+            .with_comparison(self.comparison)
+            .with_dry_run(self.dry_run)
+            .with_filter(self.filter.clone())
+            .with_client(client.clone());
+        let mut gcs = GcsSource::new(self.force_overwrite, self.concurrency)
+            .with_comparison(self.comparison)
+            .with_dry_run(self.dry_run)
+            .with_filter(self.filter)
+            .with_client(client);
+        local.progress = self.progress.clone();
+        gcs.progress = self.progress;
+        Sync {
+            local,
+            gcs,
+            retry: self.retry,
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use crate::api::*;
+use crate::crypto::plaintext_size_crc32c;
+use crate::error::*;
+use crate::gcs::GcsSource;
+use crate::local::LocalSource;
+use crate::names::*;
+use crate::posix::PosixAttrs;
+use crate::util::*;
+use crate::Result;
+use cloud_storage::object::Object;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What to do with a file changed both locally and remotely since the last sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictResolution {
+    /// The side modified last wins, a modification wins over a deletion
+    #[default]
+    NewerWins,
+    /// The remote version is downloaded while the local one is renamed
+    /// to `[filename].conflict-[unix time]` and uploaded as well,
+    /// a modification wins over a deletion
+    KeepBoth,
+    /// Conflicting files are left untouched on both sides,
+    /// the sync fails with [Error::Conflicts] after everything else is synced
+    Fail,
+}
+
+/// Two-way sync of a local directory and a bucket prefix
+///
+/// The state of every file after the last sync is stored in a local json file,
+/// changes made on either side since then are propagated to the other one,
+/// including deletions. Files changed on both sides are conflicts.
+#[derive(Debug)]
+pub struct TwoWaySync {
+    local: LocalSource,
+    gcs: GcsSource,
+    state_path: PathBuf,
+    resolution: ConflictResolution,
+}
+
+/// Last synced state of every file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct State {
+    files: BTreeMap<String, FileState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState {
+    generation: i64,
+    size: u64,
+    /// local modification time, nanoseconds since the unix epoch
+    mtime: i64,
+    crc32c: u32,
+}
+
+#[derive(Debug, Clone)]
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+}
+
+/// A file known to the state, the local directory or the bucket
+struct Entry<'a> {
+    key: String,
+    object_name: String,
+    base: Option<FileState>,
+    local: Option<&'a LocalFile>,
+    remote: Option<&'a Object>,
+}
+
+enum Outcome {
+    Synced(Option<FileState>),
+    Conflict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Same,
+    Modified,
+    Deleted,
+}
+
+impl TwoWaySync {
+    /// `local` and `gcs` sources provide the options used for uploads and downloads,
+    /// files excluded by the filter of either one are left alone on both sides
+    /// and a dry run of either one only logs what would be done.
+    /// `state_path` is a file where the last synced state is kept between runs,
+    /// it is excluded from the sync if placed inside the synced directory.
+    /// Gzip encoded objects are always decompressed, remote content is compared uncompressed
+    pub fn new(local: LocalSource, gcs: GcsSource, state_path: impl Into<PathBuf>) -> Self {
+        Self {
+            local,
+            gcs: gcs.with_gzip_decompression(true),
+            state_path: state_path.into(),
+            resolution: ConflictResolution::default(),
+        }
+    }
+
+    /// Sets conflict resolution, [ConflictResolution::NewerWins] by default
+    pub fn with_conflict_resolution(mut self, resolution: ConflictResolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Syncs local directory and [bucket]/[prefix] in both directions
+    ///
+    /// Returns the count of uploads, downloads and deletions
+    pub async fn sync(
+        &self,
+        local_dir: impl AsRef<Path>,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<usize> {
+        let local_dir = std::path::absolute(local_dir.as_ref()).context(Io {
+            path: local_dir.as_ref(),
+        })?;
+        let mut state = self.load_state().await?;
+        let state_keys = self.state_keys(&local_dir)?;
+        let local_files = self.scan_local(&local_dir, &state_keys).await?;
+        let remote_objects = self.list_remote(bucket, prefix, &state_keys).await?;
+
+        let keys: BTreeSet<String> = state
+            .files
+            .keys()
+            .chain(local_files.keys())
+            .chain(remote_objects.keys())
+            .filter(|key| !self.excludes(key) && !state_keys.contains(*key))
+            .cloned()
+            .collect();
+
+        let mut count = 0;
+        let mut conflicts = vec![];
+        for key in keys {
+            let entry = Entry {
+                object_name: join_object_name(prefix, &key),
+                base: state.files.get(&key).copied(),
+                local: local_files.get(&key),
+                remote: remote_objects.get(&key),
+                key,
+            };
+            match self
+                .sync_entry(&local_dir, bucket, prefix, &entry, &mut state, &mut count)
+                .await
+            {
+                Ok(Outcome::Synced(Some(file_state))) => {
+                    state.files.insert(entry.key, file_state);
+                }
+                Ok(Outcome::Synced(None)) => {
+                    state.files.remove(&entry.key);
+                }
+                // the stored state is kept so the conflict is detected again
+                Ok(Outcome::Conflict) => conflicts.push(entry.object_name),
+                // the object was changed by someone else right after it was listed
+                Err(Error::PreconditionFailed { object, .. }) => {
+                    log::warn!("Conflict: {} changed during sync", object);
+                    conflicts.push(object)
+                }
+                Err(e) => {
+                    // keep what is already synced
+                    if !self.dry_run() {
+                        self.save_state(&state).await?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if !self.dry_run() {
+            self.save_state(&state).await?;
+        }
+
+        if conflicts.is_empty() {
+            Ok(count)
+        } else {
+            Err(Error::Conflicts { paths: conflicts })
+        }
+    }
+
+    /// Propagates a change of a single file, returns its new state
+    async fn sync_entry(
+        &self,
+        local_dir: &Path,
+        bucket: &str,
+        prefix: &str,
+        entry: &Entry<'_>,
+        state: &mut State,
+        count: &mut usize,
+    ) -> Result<Outcome> {
+        let Entry {
+            key,
+            object_name,
+            base,
+            local,
+            remote,
+        } = entry;
+        let (key, object_name, local, remote) =
+            (key.as_str(), object_name.as_str(), *local, *remote);
+        let local_change = Self::local_change(*base, local).await?;
+        let remote_change = Self::remote_change(*base, remote);
+        log::trace!(
+            "{}: local {:?}, remote {:?}",
+            key,
+            local_change,
+            remote_change
+        );
+
+        let resolved = match (local_change, remote_change) {
+            (Change::Same, Change::Same) => match (local, remote) {
+                (Some(local), Some(remote)) => Some(Self::file_state(local, remote)),
+                _ => None,
+            },
+            (Change::Modified, Change::Same) => {
+                *count += 1;
+                self.upload(local, key, bucket, object_name, remote).await?
+            }
+            (Change::Same, Change::Modified) => {
+                *count += 1;
+                self.download(local_dir, key, bucket, remote).await?
+            }
+            (Change::Deleted, Change::Same) => {
+                *count += self.delete_remote(bucket, object_name, remote).await?;
+                None
+            }
+            (Change::Same, Change::Deleted) => {
+                *count += self.delete_local(local).await?;
+                None
+            }
+            (Change::Deleted, Change::Deleted) => None,
+            (Change::Modified, Change::Modified) if Self::same_content(local, remote).await? => {
+                local
+                    .zip(remote)
+                    .map(|(local, remote)| Self::file_state(local, remote))
+            }
+            _ => {
+                log::warn!("Conflict: {} changed on both sides", object_name);
+                match self.resolution {
+                    ConflictResolution::Fail => return Ok(Outcome::Conflict),
+                    ConflictResolution::KeepBoth => {
+                        if let (Some(local), Some(_)) = (local, remote) {
+                            if self.dry_run() {
+                                log::info!(
+                                    "Would rename {:?} and download gs://{}/{}",
+                                    local.path,
+                                    bucket,
+                                    object_name
+                                );
+                                *count += 2;
+                                return Ok(Outcome::Synced(None));
+                            }
+                            let renamed = Self::rename_conflicting(local).await?;
+                            let renamed_key = self.relative_name(local_dir, &renamed.path)?;
+                            let renamed_name = join_object_name(prefix, &renamed_key);
+                            if let Some(renamed_state) = self
+                                .upload(Some(&renamed), &renamed_key, bucket, &renamed_name, None)
+                                .await?
+                            {
+                                state.files.insert(renamed_key, renamed_state);
+                            }
+                            *count += 2;
+                            self.download(local_dir, key, bucket, remote).await?
+                        } else {
+                            *count += 1;
+                            self.keep_modified(local_dir, key, bucket, object_name, local, remote)
+                                .await?
+                        }
+                    }
+                    ConflictResolution::NewerWins => {
+                        *count += 1;
+                        match (local, remote) {
+                            (Some(local), Some(remote))
+                                if local.mtime
+                                    > remote.updated.timestamp_nanos_opt().unwrap_or(0) =>
+                            {
+                                self.upload(Some(local), key, bucket, object_name, Some(remote))
+                                    .await?
+                            }
+                            (Some(_), Some(_)) => {
+                                self.download(local_dir, key, bucket, remote).await?
+                            }
+                            _ => {
+                                self.keep_modified(
+                                    local_dir,
+                                    key,
+                                    bucket,
+                                    object_name,
+                                    local,
+                                    remote,
+                                )
+                                .await?
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        Ok(Outcome::Synced(resolved))
+    }
+
+    async fn local_change(base: Option<FileState>, local: Option<&LocalFile>) -> Result<Change> {
+        Ok(match (base, local) {
+            (None, None) => Change::Same,
+            (None, Some(_)) => Change::Modified,
+            (Some(_), None) => Change::Deleted,
+            (Some(base), Some(local)) => {
+                if local.size != base.size {
+                    Change::Modified
+                } else if local.mtime == base.mtime
+                    || file_crc32c(&local.path)
+                        .await
+                        .context(Io { path: &local.path })?
+                        == base.crc32c
+                {
+                    Change::Same
+                } else {
+                    Change::Modified
+                }
+            }
+        })
+    }
+
+    fn remote_change(base: Option<FileState>, remote: Option<&Object>) -> Change {
+        match (base, remote) {
+            (None, None) => Change::Same,
+            (None, Some(_)) => Change::Modified,
+            (Some(_), None) => Change::Deleted,
+            (Some(base), Some(remote)) => {
+                if remote.generation == base.generation
+                    || plaintext_size_crc32c(remote) == (base.size, base.crc32c)
+                {
+                    Change::Same
+                } else {
+                    Change::Modified
+                }
+            }
+        }
+    }
+
+    async fn same_content(local: Option<&LocalFile>, remote: Option<&Object>) -> Result<bool> {
+        match (local, remote.map(plaintext_size_crc32c)) {
+            (Some(local), Some((size, crc32c))) if local.size == size => {
+                Ok(file_crc32c(&local.path)
+                    .await
+                    .context(Io { path: &local.path })?
+                    == crc32c)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn file_state(local: &LocalFile, remote: &Object) -> FileState {
+        let (size, crc32c) = plaintext_size_crc32c(remote);
+        FileState {
+            generation: remote.generation,
+            size,
+            mtime: local.mtime,
+            crc32c,
+        }
+    }
+
+    /// Propagates whichever side still exists when the other one was deleted
+    async fn keep_modified(
+        &self,
+        local_dir: &Path,
+        key: &str,
+        bucket: &str,
+        object_name: &str,
+        local: Option<&LocalFile>,
+        remote: Option<&Object>,
+    ) -> Result<Option<FileState>> {
+        if local.is_some() {
+            self.upload(local, key, bucket, object_name, remote).await
+        } else {
+            self.download(local_dir, key, bucket, remote).await
+        }
+    }
+
+    /// Uploads a local file replacing the listed remote generation,
+    /// or only if there is still no object when nothing was listed
+    async fn upload(
+        &self,
+        local: Option<&LocalFile>,
+        key: &str,
+        bucket: &str,
+        object_name: &str,
+        remote: Option<&Object>,
+    ) -> Result<Option<FileState>> {
+        let local = match local {
+            Some(local) => local,
+            None => return Ok(None),
+        };
+        if self.dry_run() {
+            log::info!(
+                "Would upload {:?} to gs://{}/{}",
+                local.path,
+                bucket,
+                object_name
+            );
+            return Ok(None);
+        }
+        let metadata = tokio::fs::metadata(&local.path)
+            .await
+            .context(Io { path: &local.path })?;
+        let attrs = PosixAttrs::from_metadata(&metadata, self.local.preserve);
+        let if_generation_match = Some(remote.map_or(0, |remote| remote.generation));
+        let object = self
+            .local
+            .upload_local_file(
+                &local.path,
+                bucket,
+                object_name,
+                &attrs,
+                &self.local.object_properties(key),
+                if_generation_match,
+            )
+            .await?;
+        Ok(Some(Self::file_state(local, &object)))
+    }
+
+    async fn download(
+        &self,
+        local_dir: &Path,
+        key: &str,
+        bucket: &str,
+        remote: Option<&Object>,
+    ) -> Result<Option<FileState>> {
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Ok(None),
+        };
+        let relative = local_relative_path(self.gcs.names, key).map_err(|problem| {
+            Error::UnrepresentableNames {
+                names: vec![(remote.name.clone(), problem)],
+            }
+        })?;
+        let path = local_dir.join(relative);
+        if self.dry_run() {
+            log::info!(
+                "Would download gs://{}/{} to {:?}",
+                bucket,
+                remote.name,
+                path
+            );
+            return Ok(None);
+        }
+        GcsSource::create_parent_dirs(self.gcs.force_overwrite, local_dir, &path).await?;
+        self.gcs.download_object(bucket, &path, remote).await?;
+        let local = Self::stat_local(path).await?;
+        Ok(Some(Self::file_state(&local, remote)))
+    }
+
+    async fn delete_remote(
+        &self,
+        bucket: &str,
+        object_name: &str,
+        remote: Option<&Object>,
+    ) -> Result<usize> {
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Ok(0),
+        };
+        if self.dry_run() {
+            log::info!("Would delete gs://{}/{}", bucket, object_name);
+            return Ok(1);
+        }
+        log::trace!("Deleting gs://{}/{}", bucket, object_name);
+        match self
+            .gcs
+            .client
+            .delete(bucket, object_name, Some(remote.generation))
+            .await
+        {
+            Err(e) if is_not_found(&e) => Ok(0),
+            deleted => {
+                check_precondition(deleted, bucket, object_name, OpSource::DeleteObject)?;
+                Ok(1)
+            }
+        }
+    }
+
+    async fn delete_local(&self, local: Option<&LocalFile>) -> Result<usize> {
+        match local {
+            Some(local) if self.dry_run() => {
+                log::info!("Would delete {:?}", local.path);
+                Ok(1)
+            }
+            Some(local) => {
+                log::trace!("Deleting {:?}", local.path);
+                tokio::fs::remove_file(&local.path)
+                    .await
+                    .context(Io { path: &local.path })?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn rename_conflicting(local: &LocalFile) -> Result<LocalFile> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut file_name = local.path.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!(".conflict-{}", now));
+        let renamed = local.path.with_file_name(file_name);
+        log::trace!("Renaming {:?} to {:?}", local.path, renamed);
+        tokio::fs::rename(&local.path, &renamed)
+            .await
+            .context(Io { path: &local.path })?;
+        Self::stat_local(renamed).await
+    }
+
+    fn dry_run(&self) -> bool {
+        self.local.dry_run || self.gcs.dry_run
+    }
+
+    /// Whether a file, or a directory named with a trailing `/`, is left out of the sync
+    fn excludes(&self, name: &str) -> bool {
+        self.local.filter.excludes(name) || self.gcs.filter.excludes(name)
+    }
+
+    /// Names of the state file and its temporary copy if they're inside the synced directory
+    fn state_keys(&self, local_dir: &Path) -> Result<BTreeSet<String>> {
+        let mut keys = BTreeSet::new();
+        for path in [self.state_path.clone(), self.tmp_state_path()] {
+            let path = std::path::absolute(&path).context(Io { path: &path })?;
+            if path.starts_with(local_dir) {
+                keys.insert(self.relative_name(local_dir, &path)?);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn tmp_state_path(&self) -> PathBuf {
+        let mut tmp_path = self.state_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        PathBuf::from(tmp_path)
+    }
+
+    fn relative_name(&self, local_dir: &Path, path: &Path) -> Result<String> {
+        let relative = path.strip_prefix(local_dir).map_err(|_| Error::WrongPath {
+            path: path.to_owned(),
+        })?;
+        let mut name = String::new();
+        for component in relative.iter() {
+            name = join_object_name(&name, &self.local.names.encode(component)?);
+        }
+        Ok(name)
+    }
+
+    async fn stat_local(path: PathBuf) -> Result<LocalFile> {
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .context(Io { path: &path })?;
+        let mtime = metadata
+            .modified()
+            .context(Io { path: &path })?
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos() as i64)
+            .unwrap_or(0);
+        Ok(LocalFile {
+            path,
+            size: metadata.len(),
+            mtime,
+        })
+    }
+
+    /// Regular files under the local directory keyed by their object name suffix,
+    /// symlinks are not followed, filtered out files and the state files are skipped
+    async fn scan_local(
+        &self,
+        local_dir: &Path,
+        state_keys: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, LocalFile>> {
+        let mut files = BTreeMap::new();
+        let mut dirs = vec![local_dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await.context(Io { path: &dir })?;
+            while let Some(entry) = entries.next_entry().await.context(Io { path: &dir })? {
+                let path = entry.path();
+                let file_type = entry.file_type().await.context(Io { path: &path })?;
+                if file_type.is_dir() {
+                    let name = self.relative_name(local_dir, &path)?;
+                    if self.excludes(&format!("{}/", name)) {
+                        log::trace!("Skip filtered {:?}", path);
+                    } else {
+                        dirs.push(path);
+                    }
+                } else if file_type.is_file() {
+                    let name = self.relative_name(local_dir, &path)?;
+                    if self.excludes(&name) || state_keys.contains(&name) {
+                        log::trace!("Skip {:?}", path);
+                    } else {
+                        files.insert(name, Self::stat_local(path).await?);
+                    }
+                } else {
+                    log::trace!("Skip {:?}", path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Objects under the prefix keyed by their name without the prefix, directory
+    /// placeholders, names that can't be stored locally, filtered out names
+    /// and names of the state files are skipped
+    async fn list_remote(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        state_keys: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, Object>> {
+        let strip_prefix = if prefix.is_empty() || prefix.ends_with('/') {
+            prefix.to_owned()
+        } else {
+            format!("{}/", prefix)
+        };
+        let mut objects = BTreeMap::new();
+        for object in self.gcs.list_objects(bucket, &strip_prefix, false).await? {
+            if object.name.ends_with('/') {
+                continue;
+            }
+            let key = match object.name.strip_prefix(&strip_prefix) {
+                Some(key) => key.to_owned(),
+                None => continue,
+            };
+            if self.excludes(&key) || state_keys.contains(&key) {
+                log::trace!("Skip gs://{}/{}", bucket, object.name);
+                continue;
+            }
+            match local_relative_path(self.gcs.names, &key) {
+                Ok(relative) if self.relative_name(Path::new(""), &relative)? == key => {
+                    objects.insert(key, object);
+                }
+                _ => log::warn!(
+                    "Skip gs://{}/{}, not representable locally",
+                    bucket,
+                    object.name
+                ),
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn load_state(&self) -> Result<State> {
+        match tokio::fs::read(&self.state_path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).context(SyncState {
+                path: &self.state_path,
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(err).context(Io {
+                path: &self.state_path,
+            }),
+        }
+    }
+
+    async fn save_state(&self, state: &State) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(state).context(SyncState {
+            path: &self.state_path,
+        })?;
+        let tmp_path = self.tmp_state_path();
+        tokio::fs::write(&tmp_path, bytes)
+            .await
+            .context(Io { path: &tmp_path })?;
+        tokio::fs::rename(&tmp_path, &self.state_path)
+            .await
+            .context(Io { path: &tmp_path })?;
+        Ok(())
+    }
+}
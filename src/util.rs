@@ -1,5 +1,5 @@
 use cloud_storage::Object;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::{fs::*, io::AsyncReadExt};
 
 pub(crate) struct FileUtil;
@@ -14,6 +14,48 @@ impl FileUtil {
     }
 }
 
+/// A file written next to its destination under a temporary name,
+/// so the destination is only replaced by complete content
+pub(crate) struct PartialFile {
+    pub(crate) file: File,
+    path: PathBuf,
+}
+
+impl PartialFile {
+    pub(crate) async fn create(path_dst: &Path) -> Result<Self, std::io::Error> {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(path_dst.file_name().unwrap_or_default());
+        name.push(format!(".{}.partial", std::process::id()));
+        let path = path_dst.with_file_name(name);
+        let file = File::create(&path).await?;
+        Ok(Self { file, path })
+    }
+
+    /// Replaces `path_dst` with the written content, keeping permissions of a replaced file
+    pub(crate) async fn persist(self, path_dst: &Path) -> Result<(), std::io::Error> {
+        let persisted = async {
+            self.file.sync_all().await?;
+            match symlink_metadata(path_dst).await {
+                Ok(replaced) if replaced.is_file() => {
+                    set_permissions(&self.path, replaced.permissions()).await?
+                }
+                _ => {}
+            }
+            rename(&self.path, path_dst).await
+        }
+        .await;
+        if persisted.is_err() {
+            let _ = remove_file(&self.path).await;
+        }
+        persisted
+    }
+
+    pub(crate) async fn remove(self) {
+        drop(self.file);
+        let _ = remove_file(&self.path).await;
+    }
+}
+
 pub(crate) trait CrcDecode {
     fn crc32c_decode(&self) -> u32;
 }
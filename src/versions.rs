@@ -0,0 +1,79 @@
+use crate::error::*;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use cloud_storage::object::Object;
+use std::collections::{BTreeMap, HashMap};
+
+/// Which generation of each object is synced from a versioned bucket
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Versions {
+    /// Live objects only, the default
+    #[default]
+    Live,
+    /// The bucket as it was at the given time: for every name the latest generation
+    /// created not later than that time, names deleted by then are left out
+    ///
+    /// Downloads overlay the destination: local files of names created later or
+    /// deleted by then are kept, restore into an empty directory to get the tree
+    /// exactly as it was
+    AsOf(DateTime<Utc>),
+    /// Explicit generations by object name, other names use their live generation
+    Generations(HashMap<String, i64>),
+}
+
+impl Versions {
+    /// Whether noncurrent object versions have to be listed
+    pub(crate) fn needs_history(&self) -> bool {
+        !matches!(self, Self::Live)
+    }
+
+    /// Picks one generation per name from a listing of `prefix` which includes noncurrent versions,
+    /// objects are returned ordered by name
+    pub(crate) fn select(&self, prefix: &str, objects: Vec<Object>) -> Result<Vec<Object>> {
+        let mut selected: BTreeMap<String, Object> = BTreeMap::new();
+        for object in objects {
+            if !self.matches(&object) {
+                continue;
+            }
+            match selected.get(&object.name) {
+                Some(current) if current.generation >= object.generation => {}
+                _ => {
+                    selected.insert(object.name.clone(), object);
+                }
+            }
+        }
+
+        if let Self::Generations(generations) = self {
+            for (object, generation) in generations {
+                if !object.starts_with(prefix) {
+                    continue;
+                }
+                let found = selected
+                    .get(object)
+                    .is_some_and(|selected| selected.generation == *generation);
+                if !found {
+                    return Err(Error::GenerationNotFound {
+                        object: object.clone(),
+                        generation: *generation,
+                    });
+                }
+            }
+        }
+
+        Ok(selected.into_values().collect())
+    }
+
+    fn matches(&self, object: &Object) -> bool {
+        match self {
+            Self::Live => object.time_deleted.is_none(),
+            Self::AsOf(time) => {
+                object.time_created <= *time
+                    && object.time_deleted.is_none_or(|deleted| deleted > *time)
+            }
+            Self::Generations(generations) => match generations.get(&object.name) {
+                Some(generation) => object.generation == *generation,
+                None => object.time_deleted.is_none(),
+            },
+        }
+    }
+}
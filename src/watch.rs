@@ -0,0 +1,345 @@
+//! Continuous upload of changed files, based on inotify
+
+use crate::api::*;
+use crate::error::*;
+use crate::local::{LocalSource, SymlinkPolicy};
+use crate::names::*;
+use crate::Result;
+use cloud_storage::ListRequest;
+use futures::stream::{StreamExt, TryStreamExt};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use snafu::{futures::TryStreamExt as SnafuTryStreamExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+
+/// Options of [LocalSource::watch]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchOptions {
+    debounce: Duration,
+    delete: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(500),
+            delete: false,
+        }
+    }
+}
+
+impl WatchOptions {
+    /// Changes are synced once nothing has changed for `debounce`, half a second by default
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Deletes objects of removed files and directories, disabled by default
+    pub fn with_delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+}
+
+/// Directories being watched
+struct Watched {
+    watches: Watches,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watched {
+    /// Watches a directory and its subdirectories
+    async fn add_recursive(&mut self, dir: &Path, symlinks: SymlinkPolicy) -> Result<()> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ATTRIB;
+        let mut seen = HashSet::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            // already removed again
+            let canonical = match fs::canonicalize(&dir).await {
+                Ok(canonical) => canonical,
+                Err(_) => continue,
+            };
+            if !seen.insert(canonical) {
+                log::warn!("Skip {:?}, symlink cycle", dir);
+                continue;
+            }
+            match self.watches.add(&dir, mask) {
+                Ok(wd) => {
+                    self.dirs.insert(wd, dir.clone());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(Io { path: dir }),
+            }
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            while let Some(entry) = entries.next_entry().await.context(TokioIo { path: &dir })? {
+                let file_type = entry
+                    .file_type()
+                    .await
+                    .context(TokioIo { path: entry.path() })?;
+                let followed = file_type.is_symlink()
+                    && symlinks == SymlinkPolicy::Follow
+                    && fs::metadata(entry.path())
+                        .await
+                        .is_ok_and(|metadata| metadata.is_dir());
+                if file_type.is_dir() || followed {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LocalSource {
+    /// Syncs local directory to [bucket_dst]/[path_dst] like [LocalSource::to_gcs]
+    /// and keeps syncing files changed after that
+    ///
+    /// Files are synced once they're closed after writing, changes are collected until
+    /// nothing has changed for the debounce period. Failures to sync a path are logged
+    /// and watching goes on, drop the future to stop watching
+    pub async fn watch(
+        &self,
+        path_src: impl AsRef<Path>,
+        bucket_dst: &str,
+        path_dst: &str,
+        options: WatchOptions,
+    ) -> Result<()> {
+        let root = path_src.as_ref().to_path_buf();
+        if !root.is_dir() {
+            return Err(Error::WrongPath { path: root });
+        }
+        let inotify = Inotify::init().context(Io { path: &root })?;
+        let mut events = inotify
+            .into_event_stream(vec![0; 4096])
+            .context(Io { path: &root })?;
+        let mut watched = Watched {
+            watches: events.watches(),
+            dirs: HashMap::new(),
+        };
+        watched.add_recursive(&root, self.symlinks).await?;
+        // changes made from now on are seen by the watches
+        let count = self.to_gcs(&root, bucket_dst, path_dst).await?;
+        log::trace!("Synced {:?} before watching, {} operations", root, count);
+
+        // changed paths are synced as they are once nothing changes for a while
+        let mut changed = HashSet::new();
+        loop {
+            let event = if changed.is_empty() {
+                events.next().await
+            } else {
+                match tokio::time::timeout(options.debounce, events.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        let paths = std::mem::take(&mut changed);
+                        self.sync_changed(&root, bucket_dst, path_dst, paths, options)
+                            .await;
+                        continue;
+                    }
+                }
+            };
+            let event = match event {
+                Some(event) => event.context(Io { path: &root })?,
+                None => return Ok(()),
+            };
+
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                log::warn!("Watch events lost, syncing {:?} again", root);
+                changed.clear();
+                changed.insert(root.clone());
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                watched.dirs.remove(&event.wd);
+                continue;
+            }
+            let path = match (watched.dirs.get(&event.wd), &event.name) {
+                (Some(dir), Some(name)) => dir.join(name),
+                _ => continue,
+            };
+            let created = event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+            if created && event.mask.contains(EventMask::ISDIR) {
+                // files created before the watch is added are synced with the directory
+                watched.add_recursive(&path, self.symlinks).await?;
+                changed.insert(path);
+            } else if event.mask.intersects(
+                EventMask::DELETE
+                    | EventMask::MOVED_FROM
+                    | EventMask::CLOSE_WRITE
+                    | EventMask::MOVED_TO,
+            ) || (event.mask.contains(EventMask::ATTRIB) && self.preserve.any())
+                // symlinks are never written
+                || (created && path.read_link().is_ok())
+            {
+                changed.insert(path);
+            }
+        }
+    }
+
+    async fn sync_changed(
+        &self,
+        root: &Path,
+        bucket: &str,
+        path_dst: &str,
+        paths: HashSet<PathBuf>,
+        options: WatchOptions,
+    ) {
+        for path in paths {
+            match self
+                .sync_change(root, bucket, path_dst, &path, options)
+                .await
+            {
+                Ok(count) => log::trace!("Synced {:?}, {} operations", path, count),
+                Err(e) => log::error!("Can't sync {:?}: {}", path, e),
+            }
+        }
+    }
+
+    /// Syncs a changed path as it is now, a removed one may have been created again,
+    /// a created one removed already
+    async fn sync_change(
+        &self,
+        root: &Path,
+        bucket: &str,
+        path_dst: &str,
+        path: &Path,
+        options: WatchOptions,
+    ) -> Result<usize> {
+        let (name, relative) = self.watched_name(root, path_dst, path)?;
+        let relative = relative.as_str();
+        let names_root = join_object_name(path_dst, "");
+
+        let metadata = match fs::symlink_metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return if options.delete && !self.filter.excludes(relative) {
+                    self.delete_objects(bucket, &name).await
+                } else {
+                    Ok(0)
+                };
+            }
+            Err(e) => return Err(e).context(TokioIo { path }),
+        };
+        let metadata = if metadata.file_type().is_symlink() {
+            match self.symlinks {
+                SymlinkPolicy::Skip => return Ok(0),
+                SymlinkPolicy::Preserve if self.filter.excludes(relative) => return Ok(0),
+                SymlinkPolicy::Preserve => {
+                    return self.sync_local_symlink_to_gcs(path, bucket, &name).await
+                }
+                SymlinkPolicy::Follow => fs::metadata(path).await.context(TokioIo { path })?,
+            }
+        } else {
+            metadata
+        };
+        if metadata.is_dir() {
+            // the root is synced as a whole, like by `to_gcs`
+            if !relative.is_empty() && self.filter.excludes(&format!("{}/", relative)) {
+                return Ok(0);
+            }
+            let canonical = fs::canonicalize(path).await.context(TokioIo { path })?;
+            self.sync_local_dir_to_gcs(
+                path.to_path_buf(),
+                bucket.to_owned(),
+                name,
+                names_root,
+                vec![canonical],
+                None,
+            )
+            .await
+        } else {
+            self.sync_local_file_to_gcs(path, bucket, &name, &names_root, None)
+                .await
+        }
+    }
+
+    /// Object name of a watched path and the name relative to `path_dst` filters match,
+    /// the root, synced again when events are lost, has an empty relative name
+    pub(crate) fn watched_name(
+        &self,
+        root: &Path,
+        path_dst: &str,
+        path: &Path,
+    ) -> Result<(String, String)> {
+        let mut name = path_dst.to_owned();
+        let mut relative = String::new();
+        for component in path.strip_prefix(root).unwrap_or(path).iter() {
+            let component = self.names.encode(component)?;
+            name = join_object_name(&name, &component);
+            relative = join_object_name(&relative, &component);
+        }
+        Ok((name, relative))
+    }
+
+    /// Deletes the object of a removed file, or all objects under a removed directory
+    async fn delete_objects(&self, bucket: &str, name: &str) -> Result<usize> {
+        let request = ListRequest {
+            prefix: Some(format!("{}/", name)),
+            ..Default::default()
+        };
+        let mut objects = self
+            .client
+            .list(bucket, request, self.server_encryption.as_ref())
+            .context(CloudStorage {
+                object: name.to_owned(),
+                op: OpSource::ListPrefix,
+            })
+            .try_fold(vec![], |mut all, list| async move {
+                all.extend(list.items);
+                Ok(all)
+            })
+            .await?;
+        match self
+            .client
+            .get(bucket, name, None, self.server_encryption.as_ref())
+            .await
+        {
+            Ok(object) => objects.push(object),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => {
+                return Err(e).context(CloudStorage {
+                    object: name.to_owned(),
+                    op: OpSource::ReadObject,
+                })
+            }
+        }
+
+        let mut count = 0;
+        for object in objects {
+            if self.dry_run {
+                log::info!("Would delete gs://{}/{}", bucket, object.name);
+                count += 1;
+                continue;
+            }
+            log::trace!("Deleting gs://{}/{}", bucket, object.name);
+            let deleted = self
+                .client
+                .delete(bucket, &object.name, Some(object.generation))
+                .await;
+            match deleted {
+                // deleted or replaced by someone else in the meantime
+                Err(e) if is_not_found(&e) || is_precondition_failure(&e) => {}
+                deleted => {
+                    deleted.context(CloudStorage {
+                        object: object.name.clone(),
+                        op: OpSource::DeleteObject,
+                    })?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}